@@ -0,0 +1,31 @@
+// There is no poseidon host function, so EMPTY_ROOT, the root of the empty utxo merkle tree that `new` puts
+// into the root history, is computed here with the client params and included by lib.rs.
+
+use std::convert::TryInto;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use fawkes_crypto::borsh::BorshSerialize;
+use pool_circuit::native::data::empty_tree_root;
+use pool_circuit::{H, POOL_PARAMS};
+use typenum::Unsigned;
+
+// TREE_HEIGHT of the contract
+const TREE_HEIGHT: usize = 32;
+
+fn main() {
+    if H::USIZE != TREE_HEIGHT {
+        panic!("pool-circuit tree height is {}, the contract needs {} (small-tree is enabled?)", H::USIZE, TREE_HEIGHT);
+    }
+
+    // U256 is little-endian u64 limbs, the same order as the borsh bytes of Num
+    let bytes = empty_tree_root(&*POOL_PARAMS).try_to_vec().unwrap();
+    let limbs = bytes.chunks(8)
+        .map(|c| format!("0x{:016x}", u64::from_le_bytes(c.try_into().unwrap())))
+        .collect::<Vec<_>>();
+
+    let path = Path::new(&env::var("OUT_DIR").unwrap()).join("empty_root.rs");
+    fs::write(path, format!("const EMPTY_ROOT: U256 = U256([{}]);\n", limbs.join(", "))).unwrap();
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -1,34 +1,188 @@
 mod verifier;
 
-use near_sdk::collections::{Vector, UnorderedSet};
-use near_sdk::{env, near_bindgen};
-use verifier::{alt_bn128_groth16verify, U256, VK, Proof};
+use near_sdk::collections::{Vector, UnorderedSet, UnorderedMap};
+use near_sdk::{env, near_bindgen, AccountId, Promise, PromiseResult};
+use near_sdk::json_types::U128;
+use verifier::{alt_bn128_groth16verify_prepared, alt_bn128_groth16verify_batch_prepared, U256, VK, PreparedVK, Proof};
 use ff_uint::borsh::{BorshSerialize, BorshDeserialize};
 use ff_uint::Uint;
+use serde::Serialize;
 
 
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
 
-// Using UnorderedSet as table with primary index is OK, because we will not remove nullifiers and root history from the set.
+// Using UnorderedSet as table with primary index is OK for nullifiers and utxos, because we will not remove them from the set.
+// Root history keeps only last MAX_ROOT_HISTORY roots, ordered by root_queue ring buffer.
 
 // todo get values from pool-crypto
 const UTXO_IN: usize = 6;
 const UTXO_OUT: usize = 2;
+// NOTE_CHUNKS[2] * 8, bit length of delta
+const DELTA_BITS: usize = 64;
 
+// number of recent roots accepted as transaction root
+const MAX_ROOT_HISTORY: u64 = 1000;
 
-fn is_unique<T:Eq+Ord+Clone>(items:&[T]) -> bool {
-    let mut v:Vec<T> = items.iter().cloned().collect();
-    v.sort();
-    for i in 1..v.len() {
-        if v[i-1] == v[i] {
-            return false;
+const GAS_FT_TRANSFER: u64 = 10_000_000_000_000;
+const GAS_FT_WITHDRAW_CALLBACK: u64 = 10_000_000_000_000;
+const GAS_WITHDRAW_CALLBACK: u64 = 10_000_000_000_000;
+
+// height of the utxo merkle tree, the pool holds at most 2^TREE_HEIGHT leaves
+const TREE_HEIGHT: u32 = 32;
+
+// root of the empty utxo merkle tree of height TREE_HEIGHT, generated by build.rs
+include!(concat!(env!("OUT_DIR"), "/empty_root.rs"));
+
+// yoctoNEAR locked per byte of contract storage
+const STORAGE_BYTE_COST: u128 = 10_000_000_000_000_000_000;
+
+// storage of a new implicit account with one full access key in bytes, a native withdrawal to an account
+// that does not exist yet can be funded with its staking
+const NEW_ACCOUNT_STORAGE: u128 = 182;
+
+// max number of items in batched view calls
+const MAX_BATCH_QUERY: usize = 1024;
+
+// size of a note encrypted by note_encrypt in pool-crypto (encrypted_note_size): epk, epk2 and the note hash
+// of 32 bytes each, the note of sum(NOTE_CHUNKS) = 64 bytes and the 32 bytes mac
+const ENCRYPTED_NOTE_SIZE: u64 = 192;
+
+// message holds UTXO_OUT encrypted notes, upper bound of the configured max_message_len
+const MAX_MESSAGE_LEN: u64 = ENCRYPTED_NOTE_SIZE * UTXO_OUT as u64;
+
+// max length of the range in the slice getters
+const MAX_SLICE: u64 = 1000;
+
+// max number of transactions in batch_transfer, the batched pairing check takes
+// 2 pairings per transaction plus 6
+const MAX_BATCH_TX: usize = 16;
+
+// asset id of the pool token in the notes, default_asset in pool-crypto
+const POOL_ASSET: U256 = U256::ZERO;
+
+// yoctoNEAR per unit of note value, note values fit NOTE_CHUNKS[2]*8 = 64 bits,
+// so the pool holds at most u64::MAX * DENOMINATION per note
+const DENOMINATION: u128 = 1_000_000_000_000_000;
+
+// converts pool units to yoctoNEAR (or token units)
+fn to_yocto(v:u64) -> u128 {
+    (v as u128).checked_mul(DENOMINATION).unwrap_or_else(|| env::panic(b"amount overflow"))
+}
+
+// converts yoctoNEAR to pool units, the amount must be an exact multiple of DENOMINATION
+fn from_yocto(amount:u128) -> u64 {
+    if amount % DENOMINATION != 0 {
+        env::panic(b"amount is not a multiple of denomination");
+    }
+    let v = amount / DENOMINATION;
+    if v > u64::MAX as u128 {
+        env::panic(b"amount out of range");
+    }
+    v as u64
+}
+
+
+// pairwise comparison without allocation, it is cheaper than sorting for UTXO_IN and UTXO_OUT sized arrays
+fn is_unique<T:Eq>(items:&[T]) -> bool {
+    for i in 0..items.len() {
+        for j in i+1..items.len() {
+            if items[i] == items[j] {
+                return false;
+            }
         }
     }
     return true;
 }
 
+// slice getters take [from, to) ranges of at most MAX_SLICE items
+fn check_slice_range(from:u64, to:u64) {
+    if from > to {
+        env::panic(b"wrong slice range");
+    }
+    if to - from > MAX_SLICE {
+        env::panic(b"slice range too large");
+    }
+}
+
+// delta is a DELTA_BITS-bit two's complement number, the same convention as parse_delta in pool-crypto
+fn parse_delta(delta:U256) -> Result<i64, &'static str> {
+    if delta > U256::from(u64::MAX >> (64 - DELTA_BITS)) {
+        return Err("delta out of range");
+    }
+    let shift = 64 - DELTA_BITS;
+    Ok(((delta.low_u64() << shift) as i64) >> shift)
+}
+
+// relayer fee is an unsigned DELTA_BITS-bit number
+fn parse_fee(fee:U256) -> Result<u64, &'static str> {
+    if fee > U256::from(u64::MAX >> (64 - DELTA_BITS)) {
+        return Err("fee out of range");
+    }
+    Ok(fee.low_u64())
+}
+
+// result of the payout transfer in its callback, callbacks can be called by the contract only
+fn payout_succeeded() -> bool {
+    if env::predecessor_account_id() != env::current_account_id() {
+        env::panic(b"callback is private");
+    }
+    match env::promise_result(0) {
+        PromiseResult::Successful(_) => true,
+        _ => false
+    }
+}
+
+// returns the deposited amount, received amount must match positive delta exactly
+fn check_deposit(delta:i64, received:u128) -> u128 {
+    let value = if delta > 0 { delta as u64 } else { 0 };
+    if from_yocto(received) != value {
+        env::panic(b"deposit does not match delta");
+    }
+    received
+}
+
+// hex of borsh (little-endian) bytes, so memo is the hex of keccak256 of the message
+fn to_hex(v:&U256) -> String {
+    v.try_to_vec().unwrap().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// data of NEP-297 event for an accepted transaction, out_hash leaves occupy [leaf_index_from, leaf_index_to)
+#[derive(Serialize)]
+struct TransferEvent {
+    root: String,
+    nullifier: Vec<String>,
+    out_hash: Vec<String>,
+    leaf_index_from: u64,
+    leaf_index_to: u64,
+    memo: String,
+    delta: i64,
+}
+
+fn transfer_event_json(txobj:&TransferAndUpdateRoot, first_leaf_index:u64, delta:i64) -> String {
+    let data = TransferEvent {
+        root: to_hex(&txobj.after_root),
+        nullifier: txobj.nullifier.iter().map(to_hex).collect(),
+        out_hash: txobj.out_hash.iter().map(to_hex).collect(),
+        leaf_index_from: first_leaf_index,
+        leaf_index_to: first_leaf_index + UTXO_OUT as u64,
+        memo: to_hex(&txobj.memo),
+        delta
+    };
+    let event = serde_json::json!({
+        "standard": "zeropool",
+        "version": "1.0.0",
+        "event": "transfer",
+        "data": [data]
+    });
+    format!("EVENT_JSON:{}", event)
+}
+
+fn log_transfer_event(txobj:&TransferAndUpdateRoot, first_leaf_index:u64, delta:i64) {
+    env::log(transfer_event_json(txobj, first_leaf_index, delta).as_bytes());
+}
+
 
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct TransferAndUpdateRoot {
@@ -36,15 +190,27 @@ pub struct TransferAndUpdateRoot {
     pub nullifier: [U256; UTXO_IN],
     pub out_hash: [U256; UTXO_OUT],
     pub delta: U256,
+    // deposited note value, must be equal to positive delta, which is bound by the transfer snark
+    pub deposit_amount: U256,
+    // relayer fee paid out of the pool, the net pool balance change is delta - fee
+    pub fee: U256,
     pub memo: U256,
+    // asset of delta and fee, only POOL_ASSET is accepted
+    pub asset: U256,
     pub message: Vec<u8>,
     pub before_root: U256,
     pub after_root: U256,
+    // receiver of the withdrawal, required for negative delta
+    pub withdraw_account: Option<AccountId>,
+    // withdrawn note value, must be equal to negative delta, which is bound by the transfer snark
+    pub withdraw_amount: U256,
 }
 
 impl TransferAndUpdateRoot {
+    // public inputs of the transfer circuit, in the order of CTransferPub:
+    // root, nullifier[UTXO_IN], out_hash[UTXO_OUT], delta, deposit_amount, withdraw_amount, fee, memo, asset
     pub fn input_vec_transfer(&self)-> Vec<U256> {
-        let mut res = Vec::with_capacity(3+UTXO_IN+UTXO_OUT);
+        let mut res = Vec::with_capacity(7+UTXO_IN+UTXO_OUT);
         res.push(self.root);
         for i in 0..UTXO_IN {
             res.push(self.nullifier[i]);
@@ -53,7 +219,11 @@ impl TransferAndUpdateRoot {
             res.push(self.out_hash[i]);
         }
         res.push(self.delta);
+        res.push(self.deposit_amount);
+        res.push(self.withdraw_amount);
+        res.push(self.fee);
         res.push(self.memo);
+        res.push(self.asset);
         res
     }
 
@@ -69,38 +239,230 @@ impl TransferAndUpdateRoot {
     }
 }
 
+// position of the first inserted out_hash leaf and the new merkle root
+#[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq)]
+pub struct TransferReceipt {
+    pub first_leaf_index: u64,
+    pub root: U256,
+}
+
+// upper bounds for the slice getters, num_roots counts all roots ever pushed
+#[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq)]
+pub struct Counts {
+    pub num_nullifiers: u64,
+    pub num_utxos: u64,
+    pub num_roots: u64,
+    pub num_messages: u64,
+}
+
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct PrivateTxEngine {
     pub nullifier:UnorderedSet<U256>,
-    pub root_history:UnorderedSet<U256>,
+    // root of the history window to its latest root number
+    pub root_history:UnorderedMap<U256, u64>,
+    // ring buffer of roots, root number i is stored at i % MAX_ROOT_HISTORY
+    pub root_queue:Vector<U256>,
+    pub num_roots:u64,
     pub utxo:UnorderedSet<U256>,
     pub message:Vector<Vec<u8>>,
-    pub vk_tx:VK,
-    pub vk_update_root:VK
+    // transfer circuit verification keys by circuit version
+    pub vks:UnorderedMap<u8, PreparedVK>,
+    pub vk_update_root:PreparedVK,
+    pub owner_id:AccountId,
+    pub total_pool_balance:u128,
+    // NEP-141 token held by the pool, native NEAR is used if not set
+    pub token_account_id:Option<AccountId>,
+    // upper bound of txobj.message length in bytes
+    pub max_message_len:u64,
+    // capacity of the utxo merkle tree
+    pub max_leaves:u64,
+    // position of the next leaf in the utxo merkle tree, kept apart from num_roots
+    // to catch an update root proof at a wrong position
+    pub num_leaves:u64,
+    // NEAR deposited by relayers for the storage of token pool transactions received by ft_on_transfer
+    pub storage_deposits:UnorderedMap<AccountId, u128>,
+    // amounts of failed withdrawals, claimed by the receivers with claim_refund
+    pub pending_refunds:UnorderedMap<AccountId, u128>
 }
 
 
 #[near_bindgen]
 impl PrivateTxEngine {
 
+    // vk_tx is registered as transfer circuit version 0, the caller becomes the owner.
+    // The root history starts with EMPTY_ROOT, the root of the client's empty tree.
+    #[init]
+    pub fn new(
+        #[serializer(borsh)] vk_tx:VK,
+        #[serializer(borsh)] vk_update_root:VK,
+        #[serializer(borsh)] token_account_id:Option<AccountId>,
+        #[serializer(borsh)] max_message_len:u64
+    ) -> Self {
+        assert!(!env::state_exists(), "Already initialized");
+        if max_message_len > MAX_MESSAGE_LEN {
+            env::panic(b"max message len too large");
+        }
+        let mut res = Self {
+            nullifier: UnorderedSet::new(b"n".to_vec()),
+            root_history: UnorderedMap::new(b"r".to_vec()),
+            root_queue: Vector::new(b"q".to_vec()),
+            num_roots: 0,
+            utxo: UnorderedSet::new(b"u".to_vec()),
+            message: Vector::new(b"m".to_vec()),
+            vks: UnorderedMap::new(b"k".to_vec()),
+            vk_update_root: PreparedVK::from(&vk_update_root),
+            owner_id: env::predecessor_account_id(),
+            total_pool_balance: 0,
+            token_account_id,
+            max_message_len,
+            max_leaves: 1 << TREE_HEIGHT,
+            num_leaves: 0,
+            storage_deposits: UnorderedMap::new(b"s".to_vec()),
+            pending_refunds: UnorderedMap::new(b"f".to_vec())
+        };
+        res.vks.insert(&0, &PreparedVK::from(&vk_tx));
+        res.push_root(&EMPTY_ROOT);
+        res
+    }
+
+    pub fn register_vk(&mut self, #[serializer(borsh)] id:u8, #[serializer(borsh)] vk:VK) {
+        self.assert_owner();
+        self.vks.insert(&id, &PreparedVK::from(&vk));
+    }
+
+    // registers the vk as the version after the latest one and returns the version
+    #[result_serializer(borsh)]
+    pub fn add_vk_version(&mut self, #[serializer(borsh)] vk:VK) -> u8 {
+        let version = self.latest_vk_version().checked_add(1).unwrap_or_else(|| env::panic(b"too many vk versions"));
+        self.register_vk(version, vk);
+        version
+    }
+
+    // proofs of a retired version are rejected as of an unknown version, the latest version can't be retired
+    pub fn retire_vk_version(&mut self, #[serializer(borsh)] version:u8) {
+        self.assert_owner();
+        if self.vks.get(&version).is_none() {
+            env::panic(b"unknown vk version");
+        }
+        if version == self.latest_vk_version() {
+            env::panic(b"can't retire the latest vk version");
+        }
+        self.vks.remove(&version);
+    }
+
+    // version for new transactions, older registered versions are accepted during a migration
+    #[result_serializer(borsh)]
+    pub fn latest_vk_version(&self) -> u8 {
+        self.vks.keys().max().unwrap_or_else(|| env::panic(b"no vk registered"))
+    }
+
+    // replaces transfer key of version 0, use register_vk for other versions
+    pub fn set_vk_tx(&mut self, #[serializer(borsh)] vk:VK) {
+        self.register_vk(0, vk);
+    }
+
+    // set_vk_tx with the VK in the snarkjs JSON format
+    pub fn set_vk_tx_json(&mut self, vk_json:String) {
+        let vk:VK = serde_json::from_str(&vk_json).unwrap_or_else(|_| env::panic(b"wrong vk json"));
+        self.set_vk_tx(vk);
+    }
+
+    pub fn set_vk_update_root(&mut self, #[serializer(borsh)] vk:VK) {
+        self.assert_owner();
+        self.vk_update_root = PreparedVK::from(&vk);
+    }
+
+    #[result_serializer(borsh)]
+    pub fn is_initialized(&self) -> bool {
+        self.num_roots != 0
+    }
+
     #[result_serializer(borsh)]
     pub fn num_tx(&self) -> u64 {
-        self.root_history.len()-1
+        if !self.is_initialized() {
+            env::panic(b"contract not initialized");
+        }
+        self.num_roots-1
     }
 
     #[result_serializer(borsh)]
     pub fn current_root(&self) -> U256 {
-        self.root_history.as_vector().get(self.num_tx()).unwrap_or_else(|| {
+        self.root_queue.get(self.num_tx() % MAX_ROOT_HISTORY).unwrap_or_else(|| {
+            env::panic(b"vector index overflow")
+        })
+    }
+
+    #[result_serializer(borsh)]
+    pub fn root_at(&self, #[serializer(borsh)] index:u64) -> U256 {
+        if index >= self.num_roots {
+            env::panic(b"root index out of range");
+        }
+        if index + MAX_ROOT_HISTORY < self.num_roots {
+            env::panic(b"root index is out of history window");
+        }
+        self.root_queue.get(index % MAX_ROOT_HISTORY).unwrap_or_else(|| {
             env::panic(b"vector index overflow")
         })
     }
 
+    // number of leaves in the utxo tree at the given root, None if the root is out of the history window.
+    // Root number i follows i transactions of UTXO_OUT leaves, so a client synced up to the root
+    // needs get_utxo_slice from this count and get_message_slice from count / UTXO_OUT
+    #[result_serializer(borsh)]
+    pub fn leaf_count_for_root(&self, #[serializer(borsh)] root:U256) -> Option<u64> {
+        self.root_history.get(&root).map(|i| i * UTXO_OUT as u64)
+    }
+
+    #[result_serializer(borsh)]
+    pub fn total_pool_balance(&self) -> u128 {
+        self.total_pool_balance
+    }
+
+    #[result_serializer(borsh)]
+    pub fn counts(&self) -> Counts {
+        Counts {
+            num_nullifiers: self.nullifier_count(),
+            num_utxos: self.utxo_count(),
+            num_roots: self.root_history_count(),
+            num_messages: self.message_count(),
+        }
+    }
+
+    #[result_serializer(borsh)]
+    pub fn utxo_count(&self) -> u64 {
+        self.utxo.len()
+    }
+
+    #[result_serializer(borsh)]
+    pub fn nullifier_count(&self) -> u64 {
+        self.nullifier.len()
+    }
+
+    #[result_serializer(borsh)]
+    pub fn message_count(&self) -> u64 {
+        self.message.len()
+    }
+
+    // upper bound of get_root_history_slice, only the last MAX_ROOT_HISTORY roots are kept
+    #[result_serializer(borsh)]
+    pub fn root_history_count(&self) -> u64 {
+        self.num_roots
+    }
+
     #[result_serializer(borsh)]
     pub fn is_nullifier(&self, #[serializer(borsh)] nullifier:U256) -> bool {
         self.nullifier.contains(&nullifier)
     }
 
+    #[result_serializer(borsh)]
+    pub fn are_nullifiers(&self, #[serializer(borsh)] nullifiers:Vec<U256>) -> Vec<bool> {
+        if nullifiers.len() > MAX_BATCH_QUERY {
+            env::panic(b"too many nullifiers in query");
+        }
+        nullifiers.iter().map(|e| self.nullifier.contains(e)).collect()
+    }
+
     #[result_serializer(borsh)]
     pub fn is_utxo(&self, #[serializer(borsh)] utxo:U256) -> bool {
         self.utxo.contains(&utxo)
@@ -108,11 +470,12 @@ impl PrivateTxEngine {
 
     #[result_serializer(borsh)]
     pub fn is_root_history(&self, #[serializer(borsh)] root_history:U256) -> bool {
-        self.root_history.contains(&root_history)
+        self.root_history.get(&root_history).is_some()
     }
 
     #[result_serializer(borsh)]
     pub fn get_nullifier_slice(&self, #[serializer(borsh)] from:u64, #[serializer(borsh)] to:u64) -> Vec<U256> {
+        check_slice_range(from, to);
         let mut result = vec![];
         for i in from .. to {
             if let Some(value) = self.nullifier.as_vector().get(i) {
@@ -124,6 +487,7 @@ impl PrivateTxEngine {
 
     #[result_serializer(borsh)]
     pub fn get_utxo_slice(&self, #[serializer(borsh)] from:u64, #[serializer(borsh)] to:u64) -> Vec<U256> {
+        check_slice_range(from, to);
         let mut result = vec![];
         for i in from .. to {
             if let Some(value) = self.utxo.as_vector().get(i) {
@@ -135,9 +499,16 @@ impl PrivateTxEngine {
 
     #[result_serializer(borsh)]
     pub fn get_root_history_slice(&self, #[serializer(borsh)] from:u64, #[serializer(borsh)] to:u64) -> Vec<U256> {
+        check_slice_range(from, to);
         let mut result = vec![];
         for i in from .. to {
-            if let Some(value) = self.root_history.as_vector().get(i) {
+            if i >= self.num_roots {
+                break;
+            }
+            if i + MAX_ROOT_HISTORY < self.num_roots {
+                continue;
+            }
+            if let Some(value) = self.root_queue.get(i % MAX_ROOT_HISTORY) {
                 result.push(value);
             }
         }
@@ -146,6 +517,7 @@ impl PrivateTxEngine {
 
     #[result_serializer(borsh)]
     pub fn get_message_slice(&self, #[serializer(borsh)] from:u64, #[serializer(borsh)] to:u64) -> Vec<Vec<u8>> {
+        check_slice_range(from, to);
         let mut result = vec![];
         for i in from .. to {
             if let Some(value) = self.message.get(i) {
@@ -159,49 +531,307 @@ impl PrivateTxEngine {
 
 
 
-    //TODO implement deposit and withdrawal
+    // kept returning bool for the existing clients, see transfer_and_update_root_v2 for the leaf positions
     #[result_serializer(borsh)]
     pub fn transfer_and_update_root(&mut self, 
         #[serializer(borsh)]  transfer_proof:Proof, 
         #[serializer(borsh)]  update_root_proof:Proof, 
-        #[serializer(borsh)]  txobj: TransferAndUpdateRoot
+        #[serializer(borsh)]  txobj: TransferAndUpdateRoot,
+        #[serializer(borsh)]  vk_version: u8
+    ) -> bool
+    {
+        self.transfer_and_update_root_v2(transfer_proof, update_root_proof, txobj, vk_version);
+        true
+    }
+
+    #[result_serializer(borsh)]
+    pub fn transfer_and_update_root_v2(&mut self, 
+        #[serializer(borsh)]  transfer_proof:Proof, 
+        #[serializer(borsh)]  update_root_proof:Proof, 
+        #[serializer(borsh)]  txobj: TransferAndUpdateRoot,
+        #[serializer(borsh)]  vk_version: u8
+    ) -> TransferReceipt
+    {
+        // attached NEAR is a deposit into a native pool (or the funding of the withdraw account for
+        // a withdrawal) and is added to the storage deposit of the caller in a token pool
+        let relayer = env::predecessor_account_id();
+        let (received, storage_deposit) = match self.token_account_id {
+            Some(_) => (0, self.add_storage_deposit(&relayer, env::attached_deposit())),
+            None => (env::attached_deposit(), 0)
+        };
+        let initial_storage = env::storage_usage();
+        let receipt = self.process_transaction(Some((&transfer_proof, &update_root_proof)), txobj, vk_version, received, storage_deposit, &relayer);
+        self.charge_storage_deposit(&relayer, storage_deposit, initial_storage);
+        receipt
+    }
+
+    // applies the transactions in order, each one must be built on the root left by the previous one.
+    // The snarks of the whole batch are checked before any state change with one pairing check per circuit.
+    // Attached NEAR is split between the positive deltas in a native pool and covers the storage in a token pool
+    #[result_serializer(borsh)]
+    pub fn batch_transfer(&mut self,
+        #[serializer(borsh)]  txs: Vec<(Proof, Proof, TransferAndUpdateRoot)>,
+        #[serializer(borsh)]  vk_version: u8
+    ) -> Vec<TransferReceipt>
+    {
+        if txs.is_empty() {
+            env::panic(b"empty batch");
+        }
+        if txs.len() > MAX_BATCH_TX {
+            env::panic(b"batch too large");
+        }
+        // checked in validate_transaction too, but a broken chain is rejected before the pairing checks
+        if txs.windows(2).any(|w| w[1].2.before_root != w[0].2.after_root) {
+            env::panic(b"broken root chain");
+        }
+
+        let vk_tx = self.vks.get(&vk_version).unwrap_or_else(|| env::panic(b"unknown vk version"));
+        let transfer_proofs = txs.iter().map(|(proof, _, _)| proof.clone()).collect::<Vec<_>>();
+        let transfer_inputs = txs.iter().map(|(_, _, txobj)| txobj.input_vec_transfer()).collect::<Vec<_>>();
+        let transfer_valid = alt_bn128_groth16verify_batch_prepared(&vk_tx, &transfer_proofs, &transfer_inputs)
+            .unwrap_or_else(|e| env::panic(e.message().as_bytes()));
+        if !transfer_valid {
+            env::panic(b"wrong transfer snark check");
+        }
+
+        let update_root_proofs = txs.iter().map(|(_, proof, _)| proof.clone()).collect::<Vec<_>>();
+        let update_root_inputs = txs.iter().enumerate()
+            .map(|(i, (_, _, txobj))| txobj.input_vec_update_root(self.num_leaves + (i * UTXO_OUT) as u64))
+            .collect::<Vec<_>>();
+        let update_root_valid = alt_bn128_groth16verify_batch_prepared(&self.vk_update_root, &update_root_proofs, &update_root_inputs)
+            .unwrap_or_else(|e| env::panic(e.message().as_bytes()));
+        if !update_root_valid {
+            env::panic(b"wrong update root snark check");
+        }
+
+        let relayer = env::predecessor_account_id();
+        let (mut received, initial_deposit) = match self.token_account_id {
+            Some(_) => (0, self.add_storage_deposit(&relayer, env::attached_deposit())),
+            None => (env::attached_deposit(), 0)
+        };
+        let batch_initial_storage = env::storage_usage();
+        let mut storage_deposit = initial_deposit;
+        let mut receipts = Vec::with_capacity(txs.len());
+        for (_, _, txobj) in txs {
+            let deposit = match parse_delta(txobj.delta) {
+                Ok(delta) if delta > 0 => to_yocto(delta as u64).min(received),
+                _ => 0
+            };
+            received -= deposit;
+
+            let initial_storage = env::storage_usage();
+            receipts.push(self.process_transaction(None, txobj, vk_version, deposit, storage_deposit, &relayer));
+            let storage_cost = env::storage_usage().saturating_sub(initial_storage) as u128 * STORAGE_BYTE_COST;
+            storage_deposit = storage_deposit.saturating_sub(storage_cost);
+        }
+        if received != 0 {
+            env::panic(b"deposit does not match delta");
+        }
+        self.charge_storage_deposit(&relayer, initial_deposit, batch_initial_storage);
+        receipts
+    }
+
+    // batch_transfer of transactions of the transfer circuit version 0
+    #[result_serializer(borsh)]
+    pub fn transfer_and_update_root_batch(&mut self,
+        #[serializer(borsh)]  items: Vec<(Proof, Proof, TransferAndUpdateRoot)>
+    ) -> Vec<TransferReceipt>
+    {
+        self.batch_transfer(items, 0)
+    }
+
+    // dry run of transfer_and_update_root for relayers, runs all checks that don't depend on the attached
+    // deposit and the storage usage, no state is changed
+    #[result_serializer(borsh)]
+    pub fn verify_transaction(&self,
+        #[serializer(borsh)]  transfer_proof:Proof,
+        #[serializer(borsh)]  update_root_proof:Proof,
+        #[serializer(borsh)]  txobj: TransferAndUpdateRoot,
+        #[serializer(borsh)]  vk_version: u8
     ) -> bool
     {
+        self.validate_transaction(Some((&transfer_proof, &update_root_proof)), &txobj, vk_version).is_ok()
+    }
+
+    // adds the attached NEAR to the storage deposit of the caller in a token pool, the storage of
+    // the deposit record itself is paid from the attached amount
+    pub fn storage_deposit(&mut self) -> U128 {
+        if self.token_account_id.is_none() {
+            env::panic(b"storage deposit is used by token pools only");
+        }
+        U128(self.add_storage_deposit(&env::predecessor_account_id(), env::attached_deposit()))
+    }
+
+    pub fn storage_balance_of(&self, account_id:AccountId) -> U128 {
+        U128(self.storage_deposits.get(&account_id).unwrap_or(0))
+    }
+
+    // NEP-141 receiver, msg is base64 encoded borsh of (transfer_proof, update_root_proof, txobj, vk_version),
+    // the relayer fee is paid to sender_id and the storage is paid from the storage deposit of sender_id
+    pub fn ft_on_transfer(&mut self, sender_id:AccountId, amount:U128, msg:String) -> U128 {
+        match self.token_account_id.as_ref() {
+            Some(token) if *token == env::predecessor_account_id() => {},
+            Some(_) => env::panic(b"wrong token contract"),
+            None => env::panic(b"pool does not accept fungible tokens")
+        }
+
+        let data = base64::decode(&msg).unwrap_or_else(|_| env::panic(b"wrong msg encoding"));
+        let (transfer_proof, update_root_proof, txobj, vk_version) = <(Proof, Proof, TransferAndUpdateRoot, u8)>::try_from_slice(&data)
+            .unwrap_or_else(|_| env::panic(b"wrong msg data"));
+
+        let storage_deposit = self.storage_deposits.get(&sender_id).unwrap_or(0);
+        let initial_storage = env::storage_usage();
+        self.process_transaction(Some((&transfer_proof, &update_root_proof)), txobj, vk_version, amount.0, storage_deposit, &sender_id);
+        self.charge_storage_deposit(&sender_id, storage_deposit, initial_storage);
+        U128(0)
+    }
+
+    // callback of ft_transfer scheduled by a token withdrawal
+    pub fn on_ft_withdraw(&mut self, receiver_id:AccountId, amount:U128) {
+        self.resolve_withdraw(&receiver_id, amount.0, None, payout_succeeded());
+    }
+
+    // callback of the NEAR transfer scheduled by a native withdrawal, the transfer fails
+    // if the receiver account does not exist. funding is the part of the transfer attached
+    // by funder (the relayer) to create the account
+    pub fn on_withdraw_complete(&mut self, receiver_id:AccountId, amount:U128, funder:Option<AccountId>, funding:U128) {
+        let funding = funder.map(|funder| (funder, funding.0));
+        self.resolve_withdraw(&receiver_id, amount.0, funding, payout_succeeded());
+    }
+
+    pub fn pending_refund(&self, account_id:AccountId) -> U128 {
+        U128(self.pending_refunds.get(&account_id).unwrap_or(0))
+    }
+
+    // pays the failed withdrawals of the caller again, once the account exists (or is registered
+    // in the token contract). A failed claim is added back to the pending refund
+    pub fn claim_refund(&mut self) -> U128 {
+        let account = env::predecessor_account_id();
+        let amount = self.pending_refunds.remove(&account).unwrap_or_else(|| env::panic(b"no pending refund"));
+        self.transfer_out(account, amount, None);
+        U128(amount)
+    }
+}
+
+impl PrivateTxEngine {
+    fn assert_owner(&self) {
+        if env::predecessor_account_id() != self.owner_id {
+            env::panic(b"only owner can call this method");
+        }
+    }
+
+    // checks shared by process_transaction and verify_transaction, returns first_leaf_index, delta and fee.
+    // proofs are None if they are already checked by batch_transfer
+    fn validate_transaction(&self, proofs:Option<(&Proof, &Proof)>, txobj:&TransferAndUpdateRoot, vk_version:u8) -> Result<(u64, i64, u64), String> {
+        let vk_tx = self.vks.get(&vk_version).ok_or("unknown vk version")?;
+
         if !is_unique(&txobj.nullifier) {
-            env::panic(b"not unique nullifier in transaction");
+            return Err("not unique nullifier in transaction".into());
         }
 
         if !is_unique(&txobj.out_hash) {
-            env::panic(b"not unique utxo in transaction");
+            return Err("not unique utxo in transaction".into());
         }
 
         if txobj.nullifier.iter().any(|&e| self.is_nullifier(e)) {
-            env::panic(b"not unique nullifier in history");
+            return Err("not unique nullifier in history".into());
         }
 
         if txobj.out_hash.iter().any(|&e| self.is_utxo(e)) {
-            env::panic(b"not unique utxo in history");
+            return Err("not unique utxo in history".into());
         }
 
         if !self.is_root_history(txobj.root) {
-            env::panic(b"no root in history");
+            return Err("no root in history".into());
         }
 
+        if txobj.asset != POOL_ASSET {
+            return Err("unsupported asset".into());
+        }
+
+        self.check_message(&txobj.message)?;
+
         if txobj.memo != U256::try_from_slice(&env::keccak256(&txobj.message)).unwrap() {
-            env::panic(b"wrong memo hash");
+            return Err("wrong memo hash".into());
         }
 
         if self.current_root() != txobj.before_root {
-            env::panic(b"wrong current root");
+            return Err("wrong current root".into());
         }
-        
-        if !alt_bn128_groth16verify(&self.vk_tx, &transfer_proof, &txobj.input_vec_transfer()) {
-            env::panic(b"wrong transfer snark check");
+
+        if let Some((transfer_proof, _)) = proofs {
+            let transfer_valid = alt_bn128_groth16verify_prepared(&vk_tx, transfer_proof, &txobj.input_vec_transfer())
+                .map_err(|e| e.message())?;
+            if !transfer_valid {
+                return Err("wrong transfer snark check".into());
+            }
         }
 
-        if !alt_bn128_groth16verify(&self.vk_update_root, &update_root_proof, &txobj.input_vec_update_root(self.num_tx() * UTXO_OUT as u64)) {
-            env::panic(b"wrong update root snark check");
+        let first_leaf_index = self.num_leaves;
+        if first_leaf_index != self.num_tx() * UTXO_OUT as u64 {
+            return Err("wrong leaf position".into());
+        }
+        if first_leaf_index + UTXO_OUT as u64 > self.max_leaves {
+            return Err("merkle tree is full".into());
+        }
+
+        if let Some((_, update_root_proof)) = proofs {
+            let update_root_valid = alt_bn128_groth16verify_prepared(&self.vk_update_root, update_root_proof, &txobj.input_vec_update_root(first_leaf_index))
+                .map_err(|e| e.message())?;
+            if !update_root_valid {
+                return Err("wrong update root snark check".into());
+            }
+        }
+
+        let delta = parse_delta(txobj.delta)?;
+
+        if txobj.deposit_amount != U256::from(if delta > 0 { delta as u64 } else { 0 }) {
+            return Err("deposit amount does not match delta".into());
+        }
+
+        self.check_withdraw(delta, txobj)?;
+
+        let fee = parse_fee(txobj.fee)?;
+
+        Ok((first_leaf_index, delta, fee))
+    }
+
+    // adds amount to the storage deposit of account and returns the new balance, the storage of
+    // the deposit record itself is paid from it
+    fn add_storage_deposit(&mut self, account:&AccountId, amount:u128) -> u128 {
+        let balance = self.storage_deposits.get(account).unwrap_or(0) + amount;
+        if amount == 0 {
+            return balance;
+        }
+        let initial_storage = env::storage_usage();
+        self.storage_deposits.insert(account, &balance);
+        let storage_cost = env::storage_usage().saturating_sub(initial_storage) as u128 * STORAGE_BYTE_COST;
+        if storage_cost > balance {
+            env::panic(b"storage is not covered");
+        }
+        self.storage_deposits.insert(account, &(balance - storage_cost));
+        balance - storage_cost
+    }
+
+    // takes the storage used since initial_storage from storage_deposit, the balance of account,
+    // the rest stays in the storage deposit of account
+    fn charge_storage_deposit(&mut self, account:&AccountId, storage_deposit:u128, initial_storage:u64) {
+        if storage_deposit > 0 {
+            let storage_cost = env::storage_usage().saturating_sub(initial_storage) as u128 * STORAGE_BYTE_COST;
+            self.storage_deposits.insert(account, &storage_deposit.saturating_sub(storage_cost));
         }
+    }
+
+    fn process_transaction(&mut self, proofs:Option<(&Proof, &Proof)>, txobj:TransferAndUpdateRoot, vk_version:u8, received:u128, storage_deposit:u128, relayer:&AccountId) -> TransferReceipt {
+        let initial_storage = env::storage_usage();
+        let (first_leaf_index, delta, fee) = self.validate_transaction(proofs, &txobj, vk_version)
+            .unwrap_or_else(|e| env::panic(e.as_bytes()));
+
+        // the payout promise is scheduled before nullifiers and roots are committed,
+        // so the state is never updated for a withdrawal that could not be created
+        let funding = if delta < 0 && self.token_account_id.is_none() { received } else { 0 };
+        self.deposit(delta, received - funding);
+        self.withdraw(delta, &txobj, funding, relayer);
 
         for e in txobj.nullifier.iter() {
             self.nullifier.insert(e);
@@ -210,13 +840,156 @@ impl PrivateTxEngine {
         for e in txobj.out_hash.iter() {
             self.utxo.insert(e);
         }
+        self.num_leaves += UTXO_OUT as u64;
         
         self.message.push(&txobj.message);
-        self.root_history.insert(&txobj.after_root);
+        self.push_root(&txobj.after_root);
+
+        let storage_cost = env::storage_usage().saturating_sub(initial_storage) as u128 * STORAGE_BYTE_COST;
+        self.pay_fee(fee, storage_cost.saturating_sub(storage_deposit), relayer);
+
+        log_transfer_event(&txobj, first_leaf_index, delta);
+
+        TransferReceipt {
+            first_leaf_index,
+            root: txobj.after_root
+        }
+    }
+
+    // message carries the encrypted output notes, so it can't be empty
+    fn check_message(&self, message:&[u8]) -> Result<(), &'static str> {
+        if message.is_empty() {
+            return Err("empty message");
+        }
+        if message.len() as u64 > self.max_message_len {
+            return Err("message too large");
+        }
+        Ok(())
+    }
+
+    fn push_root(&mut self, root:&U256) {
+        let index = self.num_roots % MAX_ROOT_HISTORY;
+        if index < self.root_queue.len() {
+            let old_root = self.root_queue.replace(index, root);
+            // a root pushed again later stays in the window
+            if self.root_history.get(&old_root) == Some(self.num_roots - MAX_ROOT_HISTORY) {
+                self.root_history.remove(&old_root);
+            }
+        } else {
+            self.root_queue.push(root);
+        }
+        self.root_history.insert(root, &self.num_roots);
+        self.num_roots += 1;
+    }
+
+    fn deposit(&mut self, delta:i64, received:u128) {
+        self.total_pool_balance += check_deposit(delta, received);
+    }
+
+    // returns the receiver and the amount of the withdrawal, None for nonnegative delta
+    fn check_withdraw(&self, delta:i64, txobj:&TransferAndUpdateRoot) -> Result<Option<(AccountId, u128)>, &'static str> {
+        let value = if delta < 0 { -(delta as i128) as u64 } else { 0 };
+        if txobj.withdraw_amount != U256::from(value) {
+            return Err("withdraw amount does not match delta");
+        }
+        if value == 0 {
+            return Ok(None);
+        }
+
+        let amount = to_yocto(value);
+        if amount > self.total_pool_balance {
+            return Err("withdraw amount exceeds pool balance");
+        }
+
+        let account = txobj.withdraw_account.clone().ok_or("no withdraw account")?;
+        if !env::is_valid_account_id(account.as_bytes()) {
+            return Err("wrong withdraw account");
+        }
+
+        Ok(Some((account, amount)))
+    }
 
-        //TODO implement deposit and withdrawal logic
+    // funding is NEAR attached by the relayer on top of the withdrawn amount, it is zero or covers
+    // the storage staking of a new account. It goes back to the relayer if the transfer fails
+    fn withdraw(&mut self, delta:i64, txobj:&TransferAndUpdateRoot, funding:u128, relayer:&AccountId) {
+        if funding > 0 && funding < NEW_ACCOUNT_STORAGE * STORAGE_BYTE_COST {
+            env::panic(b"account funding is too small");
+        }
+        let withdrawal = self.check_withdraw(delta, txobj).unwrap_or_else(|e| env::panic(e.as_bytes()));
+        if let Some((account, amount)) = withdrawal {
+            self.total_pool_balance -= amount;
+            let funding = if funding > 0 { Some((relayer.clone(), funding)) } else { None };
+            self.transfer_out(account, amount, funding);
+        }
+    }
+
+    // storage_cost is the storage staking not covered by an attached or a registered storage deposit,
+    // it is kept out of the fee, so it can be paid only in a native pool
+    fn pay_fee(&mut self, fee:u64, storage_cost:u128, relayer:&AccountId) {
+        let amount = to_yocto(fee);
+        if amount > self.total_pool_balance {
+            env::panic(b"fee exceeds pool balance");
+        }
+
+        if storage_cost > 0 && (self.token_account_id.is_some() || storage_cost > amount) {
+            env::panic(b"storage is not covered");
+        }
+
+        self.total_pool_balance -= storage_cost;
+        if amount > storage_cost {
+            self.total_pool_balance -= amount - storage_cost;
+            self.transfer_out(relayer.clone(), amount - storage_cost, None);
+        }
+    }
+
+    // Withdrawal is resolved asynchronously, but it is not a two-phase commit: the nullifiers and the new
+    // root are committed in this call and are never reverted by the callback. Other transactions may be
+    // appended to the tree before the callback runs, so its root can't be rolled back, and the outputs of
+    // the transaction stay in the tree and spendable, so reverting the nullifiers would let the inputs be
+    // spent twice. Instead the amount leaves the pool balance here and becomes a pending refund of the
+    // receiver in on_withdraw_complete or on_ft_withdraw if the transfer fails, see claim_refund.
+    // funding (native only) is sent along with amount and is refunded to its funder on failure.
+    fn transfer_out(&mut self, account:AccountId, amount:u128, funding:Option<(AccountId, u128)>) {
+        match self.token_account_id.clone() {
+            None => {
+                let (funder, funding) = funding.map_or((None, 0), |(funder, funding)| (Some(funder), funding));
+                let args = serde_json::json!({
+                    "receiver_id": account,
+                    "amount": U128(amount),
+                    "funder": funder,
+                    "funding": U128(funding)
+                }).to_string().into_bytes();
+                Promise::new(account)
+                    .transfer(amount + funding)
+                    .then(Promise::new(env::current_account_id())
+                        .function_call(b"on_withdraw_complete".to_vec(), args, 0, GAS_WITHDRAW_CALLBACK));
+            },
+            Some(token) => {
+                let args = serde_json::json!({
+                    "receiver_id": account,
+                    "amount": U128(amount)
+                }).to_string().into_bytes();
+                Promise::new(token)
+                    .function_call(b"ft_transfer".to_vec(), args.clone(), 1, GAS_FT_TRANSFER)
+                    .then(Promise::new(env::current_account_id())
+                        .function_call(b"on_ft_withdraw".to_vec(), args, 0, GAS_FT_WITHDRAW_CALLBACK));
+            }
+        }
+    }
+
+    fn resolve_withdraw(&mut self, receiver_id:&AccountId, amount:u128, funding:Option<(AccountId, u128)>, success:bool) {
+        if !success {
+            self.add_refund(receiver_id, amount);
+            if let Some((funder, funding)) = funding {
+                self.add_refund(&funder, funding);
+            }
+            env::log(format!("withdrawal of {} to {} failed, the refund can be claimed", amount, receiver_id).as_bytes());
+        }
+    }
 
-        return true;
+    fn add_refund(&mut self, account:&AccountId, amount:u128) {
+        let refund = self.pending_refunds.get(account).unwrap_or(0) + amount;
+        self.pending_refunds.insert(account, &refund);
     }
 }
 
@@ -340,4 +1113,1087 @@ mod tests { /*
     }
     */
 
+    use super::*;
+    use near_sdk::MockedBlockchain;
+    use near_sdk::{testing_env, VMContext};
+
+    fn get_context(attached_deposit: u128) -> VMContext {
+        VMContext {
+            current_account_id: "alice_near".to_string(),
+            signer_account_id: "bob_near".to_string(),
+            signer_account_pk: vec![0, 1, 2],
+            predecessor_account_id: "carol_near".to_string(),
+            input: vec![],
+            block_index: 0,
+            block_timestamp: 0,
+            account_balance: 0,
+            account_locked_balance: 0,
+            storage_usage: 0,
+            attached_deposit,
+            prepaid_gas: 10u64.pow(18),
+            random_seed: vec![0, 1, 2],
+            is_view: false,
+            output_data_receivers: vec![],
+            epoch_height: 0,
+        }
+    }
+
+    // logs of the calls made since the last testing_env!
+    fn get_logs() -> Vec<String> {
+        env::BLOCKCHAIN_INTERFACE.with(|b| b.borrow_mut().as_mut().unwrap().as_mut_mocked_blockchain().unwrap().logs())
+    }
+
+    fn zero_vk() -> VK {
+        VK::try_from_slice(&[0u8; 2*32 + 3*4*32 + 4]).unwrap()
+    }
+
+    fn zero_proof() -> Proof {
+        Proof::try_from_slice(&[0u8; 2*32 + 4*32 + 2*32]).unwrap()
+    }
+
+    // vk with all points at infinity, accepts the zero proof for any input of the given length
+    fn accept_all_vk(num_inputs:usize) -> VK {
+        let mut vk_data = vec![0u8; 2*32 + 3*4*32];
+        vk_data.extend(&(num_inputs as u32 + 1).to_le_bytes());
+        vk_data.extend(vec![0u8; (num_inputs + 1)*2*32]);
+        VK::try_from_slice(&vk_data).unwrap()
+    }
+
+    // fee covering the storage of a test transaction
+    const STORAGE_FEE: u64 = 100_000;
+
+    fn accept_all_engine() -> PrivateTxEngine {
+        let mut contract = PrivateTxEngine::new(accept_all_vk(7+UTXO_IN+UTXO_OUT), accept_all_vk(3+UTXO_OUT), None, MAX_MESSAGE_LEN);
+        contract.total_pool_balance = 10 * STORAGE_FEE as u128 * DENOMINATION;
+        contract
+    }
+
+    // transaction passing all checks of accept_all_engine, seed makes nullifiers and utxos unique
+    fn transfer_txobj(contract:&PrivateTxEngine, seed:u64) -> TransferAndUpdateRoot {
+        let mut nullifier = [U256::ZERO; UTXO_IN];
+        for i in 0..UTXO_IN {
+            nullifier[i] = U256::from(seed * UTXO_IN as u64 + i as u64 + 1);
+        }
+        let mut out_hash = [U256::ZERO; UTXO_OUT];
+        for i in 0..UTXO_OUT {
+            out_hash[i] = U256::from(seed * UTXO_OUT as u64 + i as u64 + 1);
+        }
+        let message = vec![1u8; 32];
+        TransferAndUpdateRoot {
+            root: contract.current_root(),
+            nullifier,
+            out_hash,
+            delta: U256::ZERO,
+            deposit_amount: U256::ZERO,
+            fee: U256::from(STORAGE_FEE),
+            memo: U256::try_from_slice(&env::keccak256(&message)).unwrap(),
+            asset: POOL_ASSET,
+            message,
+            before_root: contract.current_root(),
+            after_root: U256::from(seed + 1000),
+            withdraw_account: None,
+            withdraw_amount: U256::ZERO,
+        }
+    }
+
+    fn get_engine() -> PrivateTxEngine {
+        PrivateTxEngine::new(zero_vk(), zero_vk(), None, MAX_MESSAGE_LEN)
+    }
+
+    // output of pool-prover export-vk for the VK of test_vk_export in pool-crypto
+    const EXPORTED_VK: &str = "yO5EICtE+JVjTbRYkayI0Y/BoOJtE9lsrqeCTKTDnxD8UjB8B51wrVsQsVrsi6Uk0b2UKGfs3AJUX2Eud5wnET/ze/CsefR74bOn50BmVoExPPDiJGRD2IejItInd/wbtAH7GstyB1Q1j9uROBBAgE2eEj/cwRcHJobe4MP1mQIsiHdC5KXrBZFMlcRQi19O5pRHJ3fra+CNrckf5PHVL1NDT3E4ah/xGoRIbB0DfMSC2AO5NCyuZzJiAMBEKEcLbiazu9JOT65EXSc7HGM9IKlQXgpITR/jikWNxJc/Jyn6KiimTBN/yj4NVjAogElMdLmVoelMa0SAen8Z5ZwkFc6j3IriiWbKQnnkocqd++FqYs4gTh2rFDvcn2YpAhAmnMf35ssgfTFSIOyLZeRQPJ/SzCQMvSq8p1TAkgF85xv+1Vwd0UmrwJXyPVWhevfis0jEd6Cw78ESIMwB7S4dJwNAnVjEBRrKGfOAAzBIiTQRVMSMY2a1nMP/vr57eJwrOYvVboNDUHw8N+u1KoT3vTQkt6+bdeUBw2X/HBbeuyLcmx9AdsbJ0QY1GGF4cgGnSx9kGtcL9UY4qMWVtJ++LAQAAABZB9VFKNzCZgjPMZ9MTfotIL1czmkU9p4L3+6udM/DCAIGsaMeBAN/AhWI+GDLJK3EPzfiVDtw9PWWv+mifJUEQqRUa63wkfB2CouGxTpfsMPlZW93gzGXl5C4lmqMSQnAYpBIHANPM/R/DtA6eMTKKgKBfqgSMjf8YwlmfckmEkbsEZYwsUj2B+ryafp/qj39z80B/33p62Wz+OdwpcIYLSyprNYGC1nyO/jlRIhqRFhx9qkBRjKz/ddvFv7bdAeyPpjCqbT/6zrE22RSdm1I+tceC6xm3OUJE3wX4d5XF5z1EXo17iShXLdYhwVcd//YzyysetRirUxRPeXNAuAh";
+
+    // VK and proof of EXPORTED_VK and EXPORTED_PROOF in the snarkjs JSON format
+    const VK_JSON: &str = r#"{"alpha_g1":["7519284530658385413481729597245720500030404990462629904897898136763311124168","7759305346990060398806855044079194419731758745769222108787862678726666900220"],"beta_g2":[["1176649506803683766765422712992584640052508597600331524207624969164785648052","12658514753455364255847347356482838872202464563210394015283712905609817617215"],["5101165399365558344990622388380303844882446238022873489370271119435994448723","21636711779296379288552588423407018283375205758461617755430164958214291425324"]],"gamma_g2":[["9563259163523751010149091049096621603384601617762202898305068080460508703482","18614172729111398410642152943077024203312410577460779291567515130504309581422"],["12621444442673662801320090583641606322810680442819355704097044349429764048796","17216172718858700295335335730679086396625358808428380281751049509583791629262"]],"delta_g2":[["19725912176908845463228350949600426717278140749628152615121896685037506347011","17691763529654559336605901395565305758251561614530184689342930207658179614206"],["20238568526203996774716543421041972327669035296195845273002576718877215923164","15710570036806237863872618522246158637753680141692137028567816236766869162809"]],"ic":[["3964469773568119779052821042808454784223031204987385635064017202325906327385","2073371815659986312890584980465551647231124680817898465902507207756050662914"],["4200764591093886258865897762205005440549504077177167612462074109108510041154","8210162094715128697891465727401339441835903868694705140685126081493645419200"],["11199418528656095984467893172993547135742541654865846474409172511469530639430","3372662532236438142930946469282900573364450071306042743217169532251229727789"],["10558449468201057926921669993065084153745189931137637082603458431351529160370","15322117100181648633046563406466145990156007727196599836948526707694418130332"]]}"#;
+    const PROOF_JSON: &str = r#"{"a":["21043037212023412872652135275628577069606762281947467181607878147879655369793","11391638837027929377007053995004384561704549985652608308694846372242854188373"],"b":[["14717031433646916944179522170475238687961921977877202038288877044720520170335","11367876662147374721764202840481604860490092766353562008338272528492341756873"],["8547188232587596773995555326777331488318150619810926443278938036838567497339","2288215547019467674158223485296290725771964506608322019365849703537867366708"]],"c":["13775297247810760300790651473298540941951313217152384330093971036306580680406","8848858450888865898290411836574982432732750169946139830040805919604759169863"]}"#;
+
+    #[test]
+    fn test_vk_json() {
+        let vk: VK = serde_json::from_str(VK_JSON).unwrap();
+        assert_eq!(base64::encode(&vk.try_to_vec().unwrap()), EXPORTED_VK);
+        assert_eq!(serde_json::to_string(&vk).unwrap(), VK_JSON);
+
+        let proof: Proof = serde_json::from_str(PROOF_JSON).unwrap();
+        assert_eq!(base64::encode(&proof.try_to_vec().unwrap()), EXPORTED_PROOF);
+        assert_eq!(serde_json::to_string(&proof).unwrap(), PROOF_JSON);
+    }
+
+    #[test]
+    fn test_set_vk_tx_json() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        contract.set_vk_tx_json(VK_JSON.to_string());
+        let vk = VK::try_from_slice(&base64::decode(EXPORTED_VK).unwrap()).unwrap();
+        assert_eq!(contract.vks.get(&0).unwrap().try_to_vec().unwrap(), PreparedVK::from(&vk).try_to_vec().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "wrong vk json")]
+    fn test_set_vk_tx_json_wrong() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        contract.set_vk_tx_json(PROOF_JSON.to_string());
+    }
+
+    #[test]
+    fn test_exported_vk() {
+        testing_env!(get_context(0));
+        let data = base64::decode(EXPORTED_VK).unwrap();
+        let vk = VK::try_from_slice(&data).unwrap();
+        assert_eq!(vk.try_to_vec().unwrap(), data);
+        let contract = PrivateTxEngine::new(vk, zero_vk(), None, MAX_MESSAGE_LEN);
+        assert!(contract.is_initialized());
+    }
+
+    // proof of the pool-prover cli for EXPORTED_VK and its public inputs, borsh in base64
+    const EXPORTED_PROOF: &str = "Qexag8d0jvm1IWZywscRBuvdSEvlGuhvVg5Qj97vhS5VFas06bgj/yXiuZ+yJ/WZWCYDYq8e5HZPITpoaHAvGckDPBplyUtn8zZ3UI4f5E1uLmxlehAkzVK33Fp8/SEZX4v8OLLT3MP/FWhDvS43u2sLvZcCstjVjbarImuLiSA0IW7UmNgG7u8x99JExO0pp0EAGJ3PiBOzyZ/PhxUPBXvOgxhwNzx0nzZzp+aSY8yhsWxFWRl6UWzmS6J/ieUS1q5Tjwq9gs4qcX6+Q9WWRpvYVboY+f4d6smQyryKdB5Hi5E8/jWGPoD9tFJDN4KVnnESrKi7fVjH6A3twUaQEw==";
+    const EXPORTED_INPUT: &str = "wjUI3hT0OcpbeyjECssKWzOu1Nr8waJySxBVamKv9A1b+Gnc3nTCaOvUJQe8Y/q7paet0ruhEO1IeCNyOZRXKvw1C0DTqBxWT2bco92ZZ6V7QHH0BwulOiLFFvYULH8l";
+
+    fn exported_test_data() -> (VK, Proof, Vec<U256>) {
+        let vk = VK::try_from_slice(&base64::decode(EXPORTED_VK).unwrap()).unwrap();
+        let proof = Proof::try_from_slice(&base64::decode(EXPORTED_PROOF).unwrap()).unwrap();
+        let input = <[U256; 3]>::try_from_slice(&base64::decode(EXPORTED_INPUT).unwrap()).unwrap();
+        (vk, proof, input.to_vec())
+    }
+
+    #[test]
+    fn test_verify_batch() {
+        testing_env!(get_context(0));
+        let (vk, proof, input) = exported_test_data();
+        let mut bad_input = input.clone();
+        bad_input[0] = bad_input[0] - U256::ONE;
+        assert!(verifier::alt_bn128_groth16verify(&vk, &proof, &input).unwrap());
+        assert!(!verifier::alt_bn128_groth16verify(&vk, &proof, &bad_input).unwrap());
+
+        let proofs = vec![proof.clone(), proof.clone(), proof.clone()];
+        assert!(verifier::alt_bn128_groth16verify_batch(&vk, &proofs[..1], &[input.clone()]).unwrap());
+        assert!(verifier::alt_bn128_groth16verify_batch(&vk, &proofs, &[input.clone(), input.clone(), input.clone()]).unwrap());
+        assert!(!verifier::alt_bn128_groth16verify_batch(&vk, &proofs, &[input.clone(), bad_input.clone(), input.clone()]).unwrap());
+        assert!(!verifier::alt_bn128_groth16verify_batch(&vk, &proofs[..1], &[bad_input]).unwrap());
+        assert!(verifier::alt_bn128_groth16verify_batch(&vk, &proofs, &[input.clone(), input.clone()]).is_err());
+        assert!(verifier::alt_bn128_groth16verify_batch(&vk, &proofs[..1], &[input[1..].to_vec()]).is_err());
+    }
+
+    #[test]
+    fn test_new() {
+        testing_env!(get_context(0));
+        let contract = PrivateTxEngine::new(zero_vk(), zero_vk(), None, MAX_MESSAGE_LEN);
+        assert_eq!(contract.num_tx(), 0);
+        assert_eq!(contract.current_root(), EMPTY_ROOT);
+        assert!(contract.is_root_history(EMPTY_ROOT));
+        assert_ne!(EMPTY_ROOT, U256::ZERO);
+    }
+
+    #[test]
+    fn test_root_at() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        contract.push_root(&U256::from(5u64));
+        assert_eq!(contract.root_at(0), EMPTY_ROOT);
+        assert_eq!(contract.root_at(1), U256::from(5u64));
+    }
+
+    #[test]
+    #[should_panic(expected = "root index out of range")]
+    fn test_root_at_out_of_range() {
+        testing_env!(get_context(0));
+        let contract = get_engine();
+        contract.root_at(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "contract not initialized")]
+    fn test_not_initialized() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        contract.num_roots = 0;
+        assert!(!contract.is_initialized());
+        contract.current_root();
+    }
+
+    #[test]
+    fn test_verify_wrong_input_len() {
+        testing_env!(get_context(0));
+        let res = verifier::alt_bn128_groth16verify(&zero_vk(), &zero_proof(), &[U256::ONE]);
+        assert_eq!(res, Err(verifier::VerifyError::WrongInputLength { expected: 0, got: 1 }));
+    }
+
+    #[test]
+    fn test_transfer_event() {
+        testing_env!(get_context(0));
+        let mut contract = accept_all_engine();
+        let mut txobj = transfer_txobj(&contract, 0);
+        txobj.delta = U256::from(u64::MAX - 2);
+        txobj.withdraw_amount = U256::from(3u64);
+        txobj.withdraw_account = Some("dave_near".to_string());
+        let memo = to_hex(&txobj.memo);
+        contract.transfer_and_update_root(zero_proof(), zero_proof(), txobj, 0);
+
+        let events = get_logs().into_iter().filter(|e| e.starts_with("EVENT_JSON:")).collect::<Vec<_>>();
+        assert_eq!(events.len(), 1);
+        let value: serde_json::Value = serde_json::from_str(&events[0]["EVENT_JSON:".len()..]).unwrap();
+        assert_eq!(value["standard"], "zeropool");
+        assert_eq!(value["event"], "transfer");
+        let data = &value["data"][0];
+        assert_eq!(data["root"], format!("e803{}", "00".repeat(30)));
+        assert_eq!(data["nullifier"].as_array().unwrap().len(), UTXO_IN);
+        assert_eq!(data["out_hash"][1], format!("02{}", "00".repeat(31)));
+        assert_eq!(data["leaf_index_from"], 0);
+        assert_eq!(data["leaf_index_to"], UTXO_OUT);
+        assert_eq!(data["memo"], memo);
+        assert_eq!(data["delta"], -3);
+    }
+
+    #[test]
+    fn test_prepared_vk_gas() {
+        testing_env!(get_context(0));
+        // points at infinity are valid inputs for the pairing check
+        let mut vk_data = vec![0u8; 2*32 + 3*4*32];
+        vk_data.extend(&[1, 0, 0, 0]);
+        vk_data.extend(&[0u8; 2*32]);
+        let vk = VK::try_from_slice(&vk_data).unwrap();
+        let pvk = PreparedVK::from(&vk);
+
+        let gas = env::used_gas();
+        assert!(verifier::alt_bn128_groth16verify(&vk, &zero_proof(), &[]).unwrap());
+        let plain_gas = env::used_gas() - gas;
+
+        let gas = env::used_gas();
+        assert!(verifier::alt_bn128_groth16verify_prepared(&pvk, &zero_proof(), &[]).unwrap());
+        let prepared_gas = env::used_gas() - gas;
+
+        println!("groth16verify gas: plain = {}, prepared = {}", plain_gas, prepared_gas);
+        assert!(prepared_gas < plain_gas);
+    }
+
+    #[test]
+    fn test_are_nullifiers() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        contract.nullifier.insert(&U256::from(1u64));
+        contract.nullifier.insert(&U256::from(3u64));
+        let query = vec![3u64, 2, 1, 1, 4].into_iter().map(U256::from).collect();
+        assert_eq!(contract.are_nullifiers(query), vec![true, false, true, true, false]);
+    }
+
+    #[test]
+    #[should_panic(expected = "too many nullifiers in query")]
+    fn test_are_nullifiers_limit() {
+        testing_env!(get_context(0));
+        let contract = get_engine();
+        contract.are_nullifiers(vec![U256::ZERO; MAX_BATCH_QUERY + 1]);
+    }
+
+    #[test]
+    fn test_register_vk() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        contract.register_vk(1, zero_vk());
+        assert!(contract.vks.get(&0).is_some());
+        assert!(contract.vks.get(&1).is_some());
+    }
+
+    #[test]
+    fn test_vk_versions() {
+        testing_env!(get_context(0));
+        let mut contract = accept_all_engine();
+        assert_eq!(contract.latest_vk_version(), 0);
+        assert_eq!(contract.add_vk_version(zero_vk()), 1);
+        assert_eq!(contract.latest_vk_version(), 1);
+
+        // v0 proofs are accepted while v1 is the latest
+        assert!(!contract.verify_transaction(zero_proof(), zero_proof(), transfer_txobj(&contract, 0), 1));
+        assert!(contract.transfer_and_update_root(zero_proof(), zero_proof(), transfer_txobj(&contract, 0), 0));
+        assert_eq!(contract.num_tx(), 1);
+
+        contract.retire_vk_version(0);
+        assert_eq!(contract.latest_vk_version(), 1);
+        assert!(!contract.verify_transaction(zero_proof(), zero_proof(), transfer_txobj(&contract, 1), 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "can't retire the latest vk version")]
+    fn test_retire_latest_vk_version() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        contract.add_vk_version(zero_vk());
+        contract.retire_vk_version(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown vk version")]
+    fn test_retire_unknown_vk_version() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        contract.retire_vk_version(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "only owner can call this method")]
+    fn test_register_vk_not_owner() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        let mut context = get_context(0);
+        context.predecessor_account_id = "dave_near".to_string();
+        testing_env!(context);
+        contract.register_vk(1, zero_vk());
+    }
+
+    #[test]
+    #[should_panic(expected = "only owner can call this method")]
+    fn test_set_vk_tx_not_owner() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        let mut context = get_context(0);
+        context.predecessor_account_id = "dave_near".to_string();
+        testing_env!(context);
+        contract.set_vk_tx(zero_vk());
+    }
+
+    #[test]
+    #[should_panic(expected = "only owner can call this method")]
+    fn test_set_vk_update_root_not_owner() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        let mut context = get_context(0);
+        context.predecessor_account_id = "dave_near".to_string();
+        testing_env!(context);
+        contract.set_vk_update_root(zero_vk());
+    }
+
+    #[test]
+    fn test_leaf_count_for_root() {
+        testing_env!(get_context(0));
+        let mut contract = accept_all_engine();
+        let empty_root = contract.current_root();
+        assert_eq!(contract.leaf_count_for_root(empty_root), Some(0));
+        assert_eq!(contract.leaf_count_for_root(U256::from(1000u64)), None);
+
+        for seed in 0..3 {
+            let txobj = transfer_txobj(&contract, seed);
+            let root = txobj.after_root;
+            let receipt = contract.transfer_and_update_root_v2(zero_proof(), zero_proof(), txobj, 0);
+            let count = contract.leaf_count_for_root(root).unwrap();
+            assert_eq!(count, receipt.first_leaf_index + UTXO_OUT as u64);
+            assert_eq!(contract.get_utxo_slice(count, count + UTXO_OUT as u64), vec![]);
+            assert_eq!(contract.get_message_slice(count / UTXO_OUT as u64, contract.message_count()), Vec::<Vec<u8>>::new());
+        }
+        assert_eq!(contract.leaf_count_for_root(empty_root), Some(0));
+        assert_eq!(contract.leaf_count_for_root(U256::from(1001u64)), Some(2 * UTXO_OUT as u64));
+        assert_eq!(contract.get_message_slice(1, contract.message_count()).len(), 2);
+    }
+
+    #[test]
+    fn test_root_history_window() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        for i in 1..=MAX_ROOT_HISTORY {
+            contract.push_root(&U256::from(i));
+        }
+        assert_eq!(contract.num_tx(), MAX_ROOT_HISTORY);
+        assert_eq!(contract.current_root(), U256::from(MAX_ROOT_HISTORY));
+        assert!(!contract.is_root_history(EMPTY_ROOT));
+        assert!(contract.is_root_history(U256::from(1u64)));
+        assert_eq!(contract.get_root_history_slice(0, 2), vec![U256::from(1u64)]);
+        assert_eq!(contract.leaf_count_for_root(EMPTY_ROOT), None);
+
+        // a root pushed again keeps its latest number after the first copy leaves the window
+        contract.push_root(&U256::from(2u64));
+        contract.push_root(&U256::from(MAX_ROOT_HISTORY + 1));
+        assert!(!contract.is_root_history(U256::from(1u64)));
+        assert_eq!(contract.leaf_count_for_root(U256::from(2u64)), Some((MAX_ROOT_HISTORY + 1) * UTXO_OUT as u64));
+    }
+
+    #[test]
+    fn test_slice_range() {
+        testing_env!(get_context(0));
+        let contract = get_engine();
+        assert!(contract.get_nullifier_slice(1, 1).is_empty());
+        assert!(contract.get_utxo_slice(1, MAX_SLICE + 1).is_empty());
+        assert_eq!(contract.get_root_history_slice(0, MAX_SLICE).len(), 1);
+        assert!(contract.get_message_slice(MAX_SLICE, 2 * MAX_SLICE).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "wrong slice range")]
+    fn test_nullifier_slice_reversed() {
+        testing_env!(get_context(0));
+        let contract = get_engine();
+        contract.get_nullifier_slice(2, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "slice range too large")]
+    fn test_nullifier_slice_too_large() {
+        testing_env!(get_context(0));
+        let contract = get_engine();
+        contract.get_nullifier_slice(1, MAX_SLICE + 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "wrong slice range")]
+    fn test_utxo_slice_reversed() {
+        testing_env!(get_context(0));
+        let contract = get_engine();
+        contract.get_utxo_slice(2, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "slice range too large")]
+    fn test_utxo_slice_too_large() {
+        testing_env!(get_context(0));
+        let contract = get_engine();
+        contract.get_utxo_slice(1, MAX_SLICE + 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "wrong slice range")]
+    fn test_root_history_slice_reversed() {
+        testing_env!(get_context(0));
+        let contract = get_engine();
+        contract.get_root_history_slice(2, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "slice range too large")]
+    fn test_root_history_slice_too_large() {
+        testing_env!(get_context(0));
+        let contract = get_engine();
+        contract.get_root_history_slice(1, MAX_SLICE + 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "wrong slice range")]
+    fn test_message_slice_reversed() {
+        testing_env!(get_context(0));
+        let contract = get_engine();
+        contract.get_message_slice(2, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "slice range too large")]
+    fn test_message_slice_too_large() {
+        testing_env!(get_context(0));
+        let contract = get_engine();
+        contract.get_message_slice(1, MAX_SLICE + 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "no root in history")]
+    fn test_root_out_of_window() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        for i in 1..=MAX_ROOT_HISTORY {
+            contract.push_root(&U256::from(i));
+        }
+        let mut txobj = withdraw_txobj(None, 0);
+        txobj.root = EMPTY_ROOT;
+        contract.transfer_and_update_root(zero_proof(), zero_proof(), txobj, 0);
+    }
+
+    #[test]
+    fn test_is_unique() {
+        assert!(is_unique::<u64>(&[]));
+        assert!(is_unique(&[1, 2, 3, 4, 5, 6]));
+        assert!(!is_unique(&[1, 2, 3, 2, 5, 6]));
+        assert!(!is_unique(&[1, 2, 3, 4, 5, 1]));
+        assert!(!is_unique(&[7; UTXO_IN]));
+        assert!(!is_unique(&[7; UTXO_OUT]));
+        assert!(is_unique(&[7]));
+    }
+
+    #[test]
+    fn test_parse_delta() {
+        testing_env!(get_context(0));
+        assert_eq!(parse_delta(U256::from(0u64)), Ok(0));
+        assert_eq!(parse_delta(U256::from(5u64)), Ok(5));
+        assert_eq!(parse_delta(U256::from(u64::MAX)), Ok(-1));
+        assert_eq!(parse_delta(U256::from(1u64 << 63)), Ok(i64::MIN));
+    }
+
+    #[test]
+    fn test_deposit() {
+        testing_env!(get_context(3 * DENOMINATION));
+        assert_eq!(check_deposit(3, env::attached_deposit()), 3 * DENOMINATION);
+    }
+
+    #[test]
+    fn test_denomination() {
+        assert_eq!(to_yocto(3), 3 * DENOMINATION);
+        assert_eq!(to_yocto(u64::MAX), u64::MAX as u128 * DENOMINATION);
+        assert_eq!(from_yocto(u64::MAX as u128 * DENOMINATION), u64::MAX);
+        assert_eq!(from_yocto(0), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "amount out of range")]
+    fn test_from_yocto_out_of_range() {
+        testing_env!(get_context(0));
+        from_yocto((u64::MAX as u128 + 1) * DENOMINATION);
+    }
+
+    #[test]
+    #[should_panic(expected = "amount is not a multiple of denomination")]
+    fn test_deposit_not_multiple() {
+        testing_env!(get_context(3 * DENOMINATION + 1));
+        check_deposit(3, env::attached_deposit());
+    }
+
+    #[test]
+    fn test_deposit_balance() {
+        testing_env!(get_context(3 * DENOMINATION));
+        let mut contract = get_engine();
+        contract.deposit(3, env::attached_deposit());
+        contract.deposit(3, env::attached_deposit());
+        assert_eq!(contract.total_pool_balance(), 6 * DENOMINATION);
+    }
+
+    fn withdraw_txobj(account:Option<AccountId>, amount:u64) -> TransferAndUpdateRoot {
+        let mut nullifier = [U256::ZERO; UTXO_IN];
+        for i in 0..UTXO_IN {
+            nullifier[i] = U256::from(i as u64 + 1);
+        }
+        let mut out_hash = [U256::ZERO; UTXO_OUT];
+        for i in 0..UTXO_OUT {
+            out_hash[i] = U256::from(i as u64 + 1);
+        }
+        TransferAndUpdateRoot {
+            root: U256::ZERO,
+            nullifier,
+            out_hash,
+            delta: U256::ZERO,
+            deposit_amount: U256::ZERO,
+            fee: U256::ZERO,
+            memo: U256::ZERO,
+            asset: POOL_ASSET,
+            message: vec![],
+            before_root: U256::ZERO,
+            after_root: U256::ZERO,
+            withdraw_account: account,
+            withdraw_amount: U256::from(amount),
+        }
+    }
+
+    #[test]
+    fn test_withdraw() {
+        testing_env!(get_context(5 * DENOMINATION));
+        let mut contract = get_engine();
+        contract.deposit(5, env::attached_deposit());
+        contract.withdraw(-2, &withdraw_txobj(Some("dave_near".to_string()), 2), 0, &"relayer_near".to_string());
+        assert_eq!(contract.total_pool_balance(), 3 * DENOMINATION);
+    }
+
+    #[test]
+    fn test_fee() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        contract.total_pool_balance = 5 * DENOMINATION;
+        contract.pay_fee(2, 0, &"relayer_near".to_string());
+        assert_eq!(contract.total_pool_balance(), 3 * DENOMINATION);
+    }
+
+    #[test]
+    fn test_fee_storage() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        contract.total_pool_balance = 5 * DENOMINATION;
+        contract.pay_fee(2, DENOMINATION, &"relayer_near".to_string());
+        assert_eq!(contract.total_pool_balance(), 3 * DENOMINATION);
+        contract.pay_fee(2, 2 * DENOMINATION, &"relayer_near".to_string());
+        assert_eq!(contract.total_pool_balance(), DENOMINATION);
+    }
+
+    #[test]
+    #[should_panic(expected = "storage is not covered")]
+    fn test_storage_not_covered() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        contract.total_pool_balance = 5 * DENOMINATION;
+        contract.pay_fee(1, DENOMINATION + 1, &"relayer_near".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "storage is not covered")]
+    fn test_storage_not_covered_token() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        contract.token_account_id = Some("token_near".to_string());
+        contract.total_pool_balance = 5 * DENOMINATION;
+        contract.pay_fee(1, 1, &"relayer_near".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "storage is not covered")]
+    fn test_transfer_storage_not_covered() {
+        testing_env!(get_context(0));
+        let mut contract = accept_all_engine();
+        let mut txobj = transfer_txobj(&contract, 0);
+        txobj.fee = U256::ZERO;
+        contract.transfer_and_update_root(zero_proof(), zero_proof(), txobj, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "unsupported asset")]
+    fn test_unsupported_asset() {
+        testing_env!(get_context(0));
+        let mut contract = accept_all_engine();
+        let mut txobj = transfer_txobj(&contract, 0);
+        txobj.asset = U256::from(1u64);
+        contract.transfer_and_update_root(zero_proof(), zero_proof(), txobj, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "deposit amount does not match delta")]
+    fn test_deposit_amount_mismatch() {
+        testing_env!(get_context(0));
+        let mut contract = accept_all_engine();
+        let mut txobj = transfer_txobj(&contract, 0);
+        txobj.deposit_amount = U256::from(1u64);
+        contract.transfer_and_update_root(zero_proof(), zero_proof(), txobj, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "wrong leaf position")]
+    fn test_wrong_leaf_position() {
+        testing_env!(get_context(0));
+        let mut contract = accept_all_engine();
+        contract.num_leaves = 1;
+        let txobj = transfer_txobj(&contract, 0);
+        contract.transfer_and_update_root(zero_proof(), zero_proof(), txobj, 0);
+    }
+
+    #[test]
+    fn test_verify_transaction() {
+        testing_env!(get_context(0));
+        let mut contract = accept_all_engine();
+        let txobj = transfer_txobj(&contract, 0);
+        let counts = contract.counts();
+        assert!(contract.verify_transaction(zero_proof(), zero_proof(), transfer_txobj(&contract, 0), 0));
+        assert_eq!(contract.counts(), counts);
+
+        contract.transfer_and_update_root(zero_proof(), zero_proof(), txobj, 0);
+        assert_eq!(contract.num_leaves, UTXO_OUT as u64);
+        assert!(contract.verify_transaction(zero_proof(), zero_proof(), transfer_txobj(&contract, 1), 0));
+    }
+
+    #[test]
+    fn test_verify_transaction_invalid() {
+        testing_env!(get_context(0));
+        let mut contract = accept_all_engine();
+        assert!(!contract.verify_transaction(zero_proof(), zero_proof(), transfer_txobj(&contract, 0), 1));
+
+        let mut txobj = transfer_txobj(&contract, 0);
+        txobj.memo = U256::ZERO;
+        assert!(!contract.verify_transaction(zero_proof(), zero_proof(), txobj, 0));
+
+        let mut txobj = transfer_txobj(&contract, 0);
+        txobj.delta = U256::from(u64::MAX);
+        txobj.withdraw_amount = U256::from(1u64);
+        assert!(!contract.verify_transaction(zero_proof(), zero_proof(), txobj, 0));
+
+        let mut txobj = transfer_txobj(&contract, 0);
+        txobj.fee = U256::from(u64::MAX) + U256::from(1u64);
+        assert!(!contract.verify_transaction(zero_proof(), zero_proof(), txobj, 0));
+
+        let txobj = transfer_txobj(&contract, 0);
+        contract.transfer_and_update_root(zero_proof(), zero_proof(), transfer_txobj(&contract, 0), 0);
+        assert!(!contract.verify_transaction(zero_proof(), zero_proof(), txobj, 0));
+
+        let contract = get_engine();
+        assert!(!contract.verify_transaction(zero_proof(), zero_proof(), transfer_txobj(&contract, 0), 0));
+    }
+
+    // the second transaction is built on the root left by the first one
+    fn batch_txs(contract:&PrivateTxEngine) -> Vec<(Proof, Proof, TransferAndUpdateRoot)> {
+        let txobj0 = transfer_txobj(contract, 0);
+        let mut txobj1 = transfer_txobj(contract, 1);
+        txobj1.before_root = txobj0.after_root;
+        vec![(zero_proof(), zero_proof(), txobj0), (zero_proof(), zero_proof(), txobj1)]
+    }
+
+    #[test]
+    fn test_batch_transfer() {
+        testing_env!(get_context(0));
+        let mut contract = accept_all_engine();
+        let receipts = contract.batch_transfer(batch_txs(&contract), 0);
+        assert_eq!(receipts, vec![
+            TransferReceipt { first_leaf_index: 0, root: U256::from(1000u64) },
+            TransferReceipt { first_leaf_index: UTXO_OUT as u64, root: U256::from(1001u64) }
+        ]);
+        assert_eq!(contract.num_tx(), 2);
+        assert_eq!(contract.num_leaves, 2 * UTXO_OUT as u64);
+        assert_eq!(contract.current_root(), U256::from(1001u64));
+    }
+
+    #[test]
+    #[should_panic(expected = "wrong transfer snark check")]
+    fn test_batch_transfer_bad_proof() {
+        testing_env!(get_context(0));
+        let mut contract = accept_all_engine();
+        let mut txs = batch_txs(&contract);
+        // a valid curve point, but not a proof accepted by accept_all_vk
+        txs[1].0 = exported_test_data().1;
+        contract.batch_transfer(txs, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "broken root chain")]
+    fn test_batch_transfer_wrong_order() {
+        testing_env!(get_context(0));
+        let mut contract = accept_all_engine();
+        let mut txs = batch_txs(&contract);
+        txs.swap(0, 1);
+        contract.batch_transfer(txs, 0);
+    }
+
+    #[test]
+    fn test_transfer_and_update_root_batch() {
+        testing_env!(get_context(0));
+        let mut contract = accept_all_engine();
+        let nullifiers = contract.nullifier_count();
+        let receipts = contract.transfer_and_update_root_batch(batch_txs(&contract));
+        assert_eq!(receipts.len(), 2);
+        assert_eq!(receipts[1].root, contract.current_root());
+        assert_eq!(contract.nullifier_count(), nullifiers + 2 * UTXO_IN as u64);
+        assert_eq!(contract.message_count(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "broken root chain")]
+    fn test_transfer_and_update_root_batch_broken_chain() {
+        testing_env!(get_context(0));
+        let mut contract = accept_all_engine();
+        let mut txs = batch_txs(&contract);
+        txs[1].2.before_root = U256::from(1u64);
+        contract.transfer_and_update_root_batch(txs);
+    }
+
+    #[test]
+    fn test_message_len() {
+        testing_env!(get_context(0));
+        let contract = get_engine();
+        assert_eq!(contract.check_message(&[0u8; MAX_MESSAGE_LEN as usize]), Ok(()));
+    }
+
+    #[test]
+    fn test_message_too_large() {
+        testing_env!(get_context(0));
+        let contract = get_engine();
+        assert_eq!(contract.check_message(&[0u8; MAX_MESSAGE_LEN as usize + 1]), Err("message too large"));
+    }
+
+    #[test]
+    #[should_panic(expected = "max message len too large")]
+    fn test_max_message_len_too_large() {
+        testing_env!(get_context(0));
+        PrivateTxEngine::new(zero_vk(), zero_vk(), None, MAX_MESSAGE_LEN + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "message too large")]
+    fn test_transfer_message_too_large() {
+        testing_env!(get_context(0));
+        let mut contract = accept_all_engine();
+        let mut txobj = transfer_txobj(&contract, 0);
+        txobj.message = vec![1u8; MAX_MESSAGE_LEN as usize + 1];
+        txobj.memo = U256::try_from_slice(&env::keccak256(&txobj.message)).unwrap();
+        contract.transfer_and_update_root(zero_proof(), zero_proof(), txobj, 0);
+    }
+
+    #[test]
+    fn test_empty_message() {
+        testing_env!(get_context(0));
+        let contract = get_engine();
+        assert_eq!(contract.check_message(&[]), Err("empty message"));
+    }
+
+    #[test]
+    fn test_relayer_fee() {
+        testing_env!(get_context(0));
+        let mut contract = accept_all_engine();
+        let balance = contract.total_pool_balance();
+
+        let mut context = get_context(0);
+        context.predecessor_account_id = "relayer_near".to_string();
+        testing_env!(context);
+
+        let txobj = transfer_txobj(&contract, 0);
+        assert_eq!(txobj.input_vec_transfer()[1+UTXO_IN+UTXO_OUT+3], txobj.fee);
+
+        let receipt = contract.transfer_and_update_root_v2(zero_proof(), zero_proof(), txobj, 0);
+        assert_eq!(receipt, TransferReceipt { first_leaf_index: 0, root: U256::from(1000u64) });
+        // the relayer gets the fee without the storage staking, both leave the pool balance
+        assert_eq!(contract.total_pool_balance(), balance - STORAGE_FEE as u128 * DENOMINATION);
+    }
+
+    #[test]
+    fn test_deposit_transfer() {
+        testing_env!(get_context(3 * DENOMINATION));
+        let mut contract = accept_all_engine();
+        let balance = contract.total_pool_balance();
+
+        let mut txobj = transfer_txobj(&contract, 0);
+        txobj.delta = U256::from(3u64);
+        txobj.deposit_amount = U256::from(3u64);
+        contract.transfer_and_update_root(zero_proof(), zero_proof(), txobj, 0);
+        assert_eq!(contract.num_tx(), 1);
+        assert_eq!(contract.total_pool_balance(), balance + 3 * DENOMINATION - STORAGE_FEE as u128 * DENOMINATION);
+    }
+
+    #[test]
+    #[should_panic(expected = "deposit does not match delta")]
+    fn test_deposit_transfer_mismatch() {
+        testing_env!(get_context(2 * DENOMINATION));
+        let mut contract = accept_all_engine();
+        let mut txobj = transfer_txobj(&contract, 0);
+        txobj.delta = U256::from(3u64);
+        txobj.deposit_amount = U256::from(3u64);
+        contract.transfer_and_update_root(zero_proof(), zero_proof(), txobj, 0);
+    }
+
+    #[test]
+    fn test_counts() {
+        testing_env!(get_context(0));
+        let mut contract = accept_all_engine();
+        assert_eq!(contract.counts(), Counts { num_nullifiers: 0, num_utxos: 0, num_roots: 1, num_messages: 0 });
+
+        let txobj = transfer_txobj(&contract, 0);
+        contract.transfer_and_update_root(zero_proof(), zero_proof(), txobj, 0);
+        assert_eq!(contract.counts(), Counts {
+            num_nullifiers: UTXO_IN as u64,
+            num_utxos: UTXO_OUT as u64,
+            num_roots: 2,
+            num_messages: 1
+        });
+        assert_eq!(contract.utxo_count(), UTXO_OUT as u64);
+        assert_eq!(contract.nullifier_count(), UTXO_IN as u64);
+        assert_eq!(contract.message_count(), 1);
+        assert_eq!(contract.root_history_count(), 2);
+        assert_eq!(contract.get_utxo_slice(0, contract.utxo_count()).len(), UTXO_OUT);
+    }
+
+    #[test]
+    #[should_panic(expected = "merkle tree is full")]
+    fn test_tree_capacity() {
+        testing_env!(get_context(0));
+        let mut contract = accept_all_engine();
+        contract.max_leaves = 2 * UTXO_OUT as u64;
+        for i in 0..2 {
+            let txobj = transfer_txobj(&contract, i);
+            contract.transfer_and_update_root(zero_proof(), zero_proof(), txobj, 0);
+        }
+        let txobj = transfer_txobj(&contract, 2);
+        contract.transfer_and_update_root(zero_proof(), zero_proof(), txobj, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "fee exceeds pool balance")]
+    fn test_fee_exceeds_balance() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        contract.pay_fee(2, 0, &"relayer_near".to_string());
+    }
+
+    #[test]
+    fn test_ft_withdraw() {
+        testing_env!(get_context(0));
+        let mut contract = PrivateTxEngine::new(zero_vk(), zero_vk(), Some("token_near".to_string()), MAX_MESSAGE_LEN);
+        contract.total_pool_balance = 5 * DENOMINATION;
+        contract.withdraw(-2, &withdraw_txobj(Some("dave_near".to_string()), 2), 0, &"relayer_near".to_string());
+        assert_eq!(contract.total_pool_balance(), 3 * DENOMINATION);
+
+        contract.resolve_withdraw(&"dave_near".to_string(), 2 * DENOMINATION, None, true);
+        assert_eq!(contract.total_pool_balance(), 3 * DENOMINATION);
+
+        assert_eq!(contract.pending_refund("dave_near".to_string()), U128(0));
+
+        contract.resolve_withdraw(&"dave_near".to_string(), 2 * DENOMINATION, None, false);
+        assert_eq!(contract.total_pool_balance(), 3 * DENOMINATION);
+        assert_eq!(contract.pending_refund("dave_near".to_string()), U128(2 * DENOMINATION));
+    }
+
+    #[test]
+    fn test_withdraw_failed_refund() {
+        testing_env!(get_context(0));
+        let mut contract = accept_all_engine();
+        let balance = contract.total_pool_balance();
+        let mut txobj = transfer_txobj(&contract, 0);
+        txobj.delta = U256::from(u64::MAX - 1);
+        txobj.withdraw_amount = U256::from(2u64);
+        txobj.withdraw_account = Some("dave_near".to_string());
+        let nullifier = txobj.nullifier[0];
+        contract.transfer_and_update_root(zero_proof(), zero_proof(), txobj, 0);
+        assert_eq!(contract.total_pool_balance(), balance - (2 + STORAGE_FEE as u128) * DENOMINATION);
+
+        // failed transfer to a missing account, the amount is claimable by the receiver and the nullifiers are kept
+        contract.resolve_withdraw(&"dave_near".to_string(), 2 * DENOMINATION, None, false);
+        assert_eq!(contract.total_pool_balance(), balance - (2 + STORAGE_FEE as u128) * DENOMINATION);
+        assert_eq!(contract.pending_refund("dave_near".to_string()), U128(2 * DENOMINATION));
+        assert!(contract.is_nullifier(nullifier));
+        assert_eq!(contract.num_tx(), 1);
+
+        let mut context = get_context(0);
+        context.predecessor_account_id = "dave_near".to_string();
+        testing_env!(context);
+        assert_eq!(contract.claim_refund(), U128(2 * DENOMINATION));
+        assert_eq!(contract.pending_refund("dave_near".to_string()), U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "no pending refund")]
+    fn test_claim_refund_empty() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        contract.claim_refund();
+    }
+
+    #[test]
+    fn test_withdraw_account_funding() {
+        testing_env!(get_context(NEW_ACCOUNT_STORAGE * STORAGE_BYTE_COST));
+        let mut contract = accept_all_engine();
+        let balance = contract.total_pool_balance();
+        let mut txobj = transfer_txobj(&contract, 0);
+        txobj.delta = U256::from(u64::MAX - 1);
+        txobj.withdraw_amount = U256::from(2u64);
+        txobj.withdraw_account = Some("dave_near".to_string());
+        contract.transfer_and_update_root(zero_proof(), zero_proof(), txobj, 0);
+        // the funding is sent to the receiver along with the withdrawal, it is not a part of the pool balance
+        assert_eq!(contract.total_pool_balance(), balance - (2 + STORAGE_FEE as u128) * DENOMINATION);
+
+        // a failed transfer returns the funding to the relayer, not to the receiver
+        let funding = NEW_ACCOUNT_STORAGE * STORAGE_BYTE_COST;
+        contract.resolve_withdraw(&"dave_near".to_string(), 2 * DENOMINATION, Some(("carol_near".to_string(), funding)), false);
+        assert_eq!(contract.pending_refund("dave_near".to_string()), U128(2 * DENOMINATION));
+        assert_eq!(contract.pending_refund("carol_near".to_string()), U128(funding));
+    }
+
+    #[test]
+    #[should_panic(expected = "account funding is too small")]
+    fn test_withdraw_account_funding_too_small() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        contract.total_pool_balance = 5 * DENOMINATION;
+        contract.withdraw(-2, &withdraw_txobj(Some("dave_near".to_string()), 2), 1, &"relayer_near".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "callback is private")]
+    fn test_on_withdraw_complete_private() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        contract.on_withdraw_complete("dave_near".to_string(), U128(DENOMINATION), None, U128(0));
+    }
+
+    #[test]
+    fn test_withdraw_max_negative_delta() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        let delta = parse_delta(U256::from(1u64 << 63)).unwrap();
+        let value = 1u64 << 63;
+        contract.total_pool_balance = value as u128 * DENOMINATION + 1;
+        contract.withdraw(delta, &withdraw_txobj(Some("dave_near".to_string()), value), 0, &"relayer_near".to_string());
+        assert_eq!(contract.total_pool_balance(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "withdraw amount exceeds pool balance")]
+    fn test_withdraw_exceeds_balance() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        contract.withdraw(-2, &withdraw_txobj(Some("dave_near".to_string()), 2), 0, &"relayer_near".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "withdraw amount does not match delta")]
+    fn test_withdraw_amount_mismatch() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        contract.withdraw(-2, &withdraw_txobj(Some("dave_near".to_string()), 3), 0, &"relayer_near".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "deposit does not match delta")]
+    fn test_deposit_mismatch() {
+        testing_env!(get_context(2 * DENOMINATION));
+        check_deposit(3, env::attached_deposit());
+    }
+
+    #[test]
+    #[should_panic(expected = "deposit does not match delta")]
+    fn test_deposit_without_positive_delta() {
+        testing_env!(get_context(DENOMINATION));
+        check_deposit(-1, env::attached_deposit());
+    }
+
+    fn token_engine() -> PrivateTxEngine {
+        PrivateTxEngine::new(accept_all_vk(7+UTXO_IN+UTXO_OUT), accept_all_vk(3+UTXO_OUT), Some("token_near".to_string()), MAX_MESSAGE_LEN)
+    }
+
+    fn ft_msg(txobj:TransferAndUpdateRoot) -> String {
+        base64::encode(&(zero_proof(), zero_proof(), txobj, 0u8).try_to_vec().unwrap())
+    }
+
+    #[test]
+    fn test_ft_on_transfer() {
+        let mut context = get_context(10u128.pow(24));
+        context.predecessor_account_id = "relayer_near".to_string();
+        testing_env!(context);
+        let mut contract = token_engine();
+        let storage_balance = contract.storage_deposit().0;
+        assert!(storage_balance > 0);
+        assert_eq!(contract.storage_balance_of("relayer_near".to_string()).0, storage_balance);
+
+        let mut context = get_context(0);
+        context.predecessor_account_id = "token_near".to_string();
+        testing_env!(context);
+        let mut txobj = transfer_txobj(&contract, 0);
+        txobj.delta = U256::from(5u64);
+        txobj.deposit_amount = U256::from(5u64);
+        txobj.fee = U256::ZERO;
+        let res = contract.ft_on_transfer("relayer_near".to_string(), U128(5 * DENOMINATION), ft_msg(txobj));
+
+        assert_eq!(res, U128(0));
+        assert_eq!(contract.num_tx(), 1);
+        assert_eq!(contract.total_pool_balance(), 5 * DENOMINATION);
+        // the storage of the transaction is taken from the storage deposit, not from the pool
+        assert!(contract.storage_balance_of("relayer_near".to_string()).0 < storage_balance);
+    }
+
+    #[test]
+    fn test_token_transfer_storage_deposit() {
+        let mut context = get_context(10u128.pow(24));
+        context.predecessor_account_id = "relayer_near".to_string();
+        testing_env!(context);
+        let mut contract = token_engine();
+        let txobj = transfer_txobj(&contract, 0);
+        contract.total_pool_balance = to_yocto(parse_fee(txobj.fee).unwrap());
+        contract.transfer_and_update_root(zero_proof(), zero_proof(), txobj, 0);
+
+        // the attached deposit not used by the storage of the transaction stays with the relayer
+        let storage_balance = contract.storage_balance_of("relayer_near".to_string()).0;
+        assert!(storage_balance > 0 && storage_balance < 10u128.pow(24));
+    }
+
+    #[test]
+    #[should_panic(expected = "storage is not covered")]
+    fn test_ft_on_transfer_no_storage_deposit() {
+        let mut context = get_context(0);
+        context.predecessor_account_id = "token_near".to_string();
+        testing_env!(context);
+        let mut contract = token_engine();
+        let mut txobj = transfer_txobj(&contract, 0);
+        txobj.delta = U256::from(5u64);
+        txobj.deposit_amount = U256::from(5u64);
+        txobj.fee = U256::ZERO;
+        contract.ft_on_transfer("relayer_near".to_string(), U128(5 * DENOMINATION), ft_msg(txobj));
+    }
+
+    #[test]
+    #[should_panic(expected = "wrong token contract")]
+    fn test_ft_on_transfer_wrong_token() {
+        testing_env!(get_context(0));
+        let mut contract = get_engine();
+        contract.token_account_id = Some("token_near".to_string());
+        contract.ft_on_transfer("dave_near".to_string(), U128(DENOMINATION), String::new());
+    }
 }
@@ -1,6 +1,7 @@
 use ff_uint::{construct_uint, Uint};
 use ff_uint::borsh::{BorshDeserialize, BorshSerialize};
 use near_sdk::env;
+use serde::{Serialize, Deserialize, Serializer, Deserializer};
 
 construct_uint! {
     pub struct U256(4);
@@ -9,36 +10,154 @@ construct_uint! {
 pub type G1 = [U256;2];
 pub type G2 = [U256;4];
 
-#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+// alt_bn128 base field modulus
+const FQ_MODULUS: U256 = U256([0x3c208c16d87cfd47, 0x97816a916871ca8d, 0xb85045b68181585d, 0x30644e72e131a029]);
+
+// U256 is a decimal string in JSON, as in the snarkjs output
+fn u256_from_dec(s:&str) -> Option<U256> {
+    if s.is_empty() {
+        return None;
+    }
+    s.chars().try_fold(U256::ZERO, |acc, c| {
+        let d = c.to_digit(10)?;
+        acc.checked_mul(U256::from(10u64))?.checked_add(U256::from(d as u64))
+    })
+}
+
+fn u256_to_dec(mut v:U256) -> String {
+    let ten = U256::from(10u64);
+    let mut digits = vec![];
+    loop {
+        digits.push(b'0' + (v % ten).low_u64() as u8);
+        v = v / ten;
+        if v == U256::ZERO {
+            break;
+        }
+    }
+    digits.reverse();
+    String::from_utf8(digits).unwrap()
+}
+
+impl Serialize for U256 {
+    fn serialize<S:Serializer>(&self, serializer:S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&u256_to_dec(*self))
+    }
+}
+
+impl<'de> Deserialize<'de> for U256 {
+    fn deserialize<D:Deserializer<'de>>(deserializer:D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        u256_from_dec(&s).ok_or_else(|| serde::de::Error::custom("wrong decimal number"))
+    }
+}
+
+// G2 is [[x.c0, x.c1], [y.c0, y.c1]] in JSON and [x.c1, x.c0, y.c1, y.c0] for the host functions
+mod g2_json {
+    use super::*;
+
+    pub fn serialize<S:Serializer>(p:&G2, serializer:S) -> Result<S::Ok, S::Error> {
+        [[p[1], p[0]], [p[3], p[2]]].serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D:Deserializer<'de>>(deserializer:D) -> Result<G2, D::Error> {
+        let [[x0, x1], [y0, y1]] = <[[U256;2];2]>::deserialize(deserializer)?;
+        Ok([x1, x0, y1, y0])
+    }
+}
+
+// JSON field names are the ones of snarkjs
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
 pub struct VK {
+    #[serde(rename = "alpha_g1")]
     alpha:G1,
+    #[serde(rename = "beta_g2", with = "g2_json")]
     beta:G2,
+    #[serde(rename = "gamma_g2", with = "g2_json")]
     gamma:G2,
+    #[serde(rename = "delta_g2", with = "g2_json")]
     delta:G2,
     ic: Vec<G1>
 }
 
-#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
 pub struct Proof {
     a:G1,
+    #[serde(with = "g2_json")]
     b:G2,
     c:G1
 }
 
-#[inline]
-pub fn alt_bn128_g1_multiexp(v:&[(G1, U256)]) -> G1{
-    let data = v.try_to_vec().unwrap_or_else(|_| env::panic(b"Cannot serialize data."));
+// VK with negated G2 points, computed once at initialization. The pairing host function accepts
+// only (G1, G2) pairs, so e(alpha, beta) cannot be cached as GT element, but with negated G2 points
+// the proof does not need the per-call G1 negation:
+// e(a, b) * e(alpha, -beta) * e(acc, -gamma) * e(c, -delta) == 1
+// The constant (alpha, -beta) operand is kept serialized.
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct PreparedVK {
+    alpha_neg_beta:Vec<u8>,
+    neg_gamma:G2,
+    neg_delta:G2,
+    ic: Vec<G1>
+}
+
+fn g2_neg(p:G2) -> G2 {
+    let neg = |c:U256| if c == U256::ZERO { c } else { FQ_MODULUS - c };
+    [p[0], p[1], neg(p[2]), neg(p[3])]
+}
+
+impl From<&VK> for PreparedVK {
+    fn from(vk:&VK) -> Self {
+        Self {
+            alpha_neg_beta: (vk.alpha, g2_neg(vk.beta)).try_to_vec().unwrap(),
+            neg_gamma: g2_neg(vk.gamma),
+            neg_delta: g2_neg(vk.delta),
+            ic: vk.ic.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    WrongInputLength { expected:usize, got:usize },
+    SerializationError
+}
+
+impl VerifyError {
+    pub fn message(&self) -> String {
+        match self {
+            VerifyError::WrongInputLength { expected, got } => format!("Wrong input len. Expected {}, got {}.", expected, got),
+            VerifyError::SerializationError => "Cannot serialize data.".to_string()
+        }
+    }
+}
+
+fn try_g1_multiexp(v:&[(G1, U256)]) -> Result<G1, VerifyError> {
+    let data = v.try_to_vec().map_err(|_| VerifyError::SerializationError)?;
     let res = env::alt_bn128_g1_multiexp(&data);
     let mut res_ptr = &res[..];
-    <G1 as BorshDeserialize>::deserialize(&mut res_ptr).unwrap_or_else(|_| env::panic(b"Cannot deserialize data."))
+    <G1 as BorshDeserialize>::deserialize(&mut res_ptr).map_err(|_| VerifyError::SerializationError)
 }
 
-#[inline]
-pub fn alt_bn128_g1_sum(v:&[(bool, G1)]) -> G1{
-    let data = v.try_to_vec().unwrap_or_else(|_| env::panic(b"Cannot serialize data."));
+fn try_g1_sum(v:&[(bool, G1)]) -> Result<G1, VerifyError> {
+    let data = v.try_to_vec().map_err(|_| VerifyError::SerializationError)?;
     let res = env::alt_bn128_g1_sum(&data);
     let mut res_ptr = &res[..];
-    <G1 as BorshDeserialize>::deserialize(&mut res_ptr).unwrap_or_else(|_| env::panic(b"Cannot deserialize data."))
+    <G1 as BorshDeserialize>::deserialize(&mut res_ptr).map_err(|_| VerifyError::SerializationError)
+}
+
+fn try_pairing_check(v:&[(G1,G2)]) -> Result<bool, VerifyError> {
+    let data = v.try_to_vec().map_err(|_| VerifyError::SerializationError)?;
+    Ok(env::alt_bn128_pairing_check(&data))
+}
+
+#[inline]
+pub fn alt_bn128_g1_multiexp(v:&[(G1, U256)]) -> G1{
+    try_g1_multiexp(v).unwrap_or_else(|e| env::panic(e.message().as_bytes()))
+}
+
+#[inline]
+pub fn alt_bn128_g1_sum(v:&[(bool, G1)]) -> G1{
+    try_g1_sum(v).unwrap_or_else(|e| env::panic(e.message().as_bytes()))
 }
 
 #[inline]
@@ -48,19 +167,18 @@ pub fn alt_bn128_g1_neg(p:G1) -> G1 {
 
 #[inline]
 pub fn alt_bn128_pairing_check(v:&[(G1,G2)]) -> bool {
-    let data = v.try_to_vec().unwrap_or_else(|_| env::panic(b"Cannot serialize data."));
-    env::alt_bn128_pairing_check(&data)
+    try_pairing_check(v).unwrap_or_else(|e| env::panic(e.message().as_bytes()))
 }
 
 
 
-pub fn alt_bn128_groth16verify(vk:&VK, proof:&Proof, input:&[U256]) -> bool {
+pub fn alt_bn128_groth16verify(vk:&VK, proof:&Proof, input:&[U256]) -> Result<bool, VerifyError> {
     if vk.ic.len() != input.len() + 1 {
-        env::panic(b"Wrong input len.");
+        return Err(VerifyError::WrongInputLength { expected: vk.ic.len().saturating_sub(1), got: input.len() });
     }
-    let neg_a = alt_bn128_g1_neg(proof.a);
+    let neg_a = try_g1_sum(&[(true, proof.a)])?;
     let acc_expr = vk.ic.iter().zip([U256::ONE].iter().chain(input.iter())).map(|(&base, &exp)| (base, exp)).collect::<Vec<_>>();
-    let acc = alt_bn128_g1_multiexp(&acc_expr);
+    let acc = try_g1_multiexp(&acc_expr)?;
 
     let pairing_expr = vec![
         (neg_a, proof.b),
@@ -69,6 +187,129 @@ pub fn alt_bn128_groth16verify(vk:&VK, proof:&Proof, input:&[U256]) -> bool {
         (proof.c, vk.delta),
     ];
 
-    alt_bn128_pairing_check(&pairing_expr)
-    
+    try_pairing_check(&pairing_expr)
+}
+
+pub fn alt_bn128_groth16verify_prepared(vk:&PreparedVK, proof:&Proof, input:&[U256]) -> Result<bool, VerifyError> {
+    if vk.ic.len() != input.len() + 1 {
+        return Err(VerifyError::WrongInputLength { expected: vk.ic.len().saturating_sub(1), got: input.len() });
+    }
+    let acc_expr = vk.ic.iter().zip([U256::ONE].iter().chain(input.iter())).map(|(&base, &exp)| (base, exp)).collect::<Vec<_>>();
+    let acc = try_g1_multiexp(&acc_expr)?;
+
+    let data = prepared_pairing_input(vk, proof, acc)?;
+    Ok(env::alt_bn128_pairing_check(&data))
+}
+
+// borsh serialized Vec<(G1, G2)> of 4 pairing operands, same as try_pairing_check input
+fn prepared_pairing_input(vk:&PreparedVK, proof:&Proof, acc:G1) -> Result<Vec<u8>, VerifyError> {
+    let ser = |v:&(G1, G2)| v.try_to_vec().map_err(|_| VerifyError::SerializationError);
+    let mut data = 4u32.try_to_vec().map_err(|_| VerifyError::SerializationError)?;
+    data.extend(ser(&(proof.a, proof.b))?);
+    data.extend(&vk.alpha_neg_beta);
+    data.extend(ser(&(acc, vk.neg_gamma))?);
+    data.extend(ser(&(proof.c, vk.neg_delta))?);
+    Ok(data)
+}
+
+// Random linear combination of the checks of the batch with 128-bit coefficients r_i:
+// prod e(r_i*a_i, b_i) * e(sum(r_i)*alpha, -beta) * e(sum(r_i*acc_i), -gamma) * e(sum(r_i*c_i), -delta) == 1
+// It takes one pairing per proof plus 3 instead of 4 per proof. A batch with an invalid proof passes
+// with probability about 2^-128, if r_i can't be predicted when the proofs are built. r_i are derived from
+// env::random_seed and the batch itself, so the sender can't choose them, but the block producer knows
+// the seed in advance. Each r_i is below 2^128, so the sums don't reach the group order for any batch size.
+pub fn alt_bn128_groth16verify_batch(vk:&VK, proofs:&[Proof], inputs:&[Vec<U256>]) -> Result<bool, VerifyError> {
+    alt_bn128_groth16verify_batch_prepared(&PreparedVK::from(vk), proofs, inputs)
+}
+
+pub fn alt_bn128_groth16verify_batch_prepared(vk:&PreparedVK, proofs:&[Proof], inputs:&[Vec<U256>]) -> Result<bool, VerifyError> {
+    if proofs.len() != inputs.len() {
+        return Err(VerifyError::WrongInputLength { expected: proofs.len(), got: inputs.len() });
+    }
+    for input in inputs.iter() {
+        if vk.ic.len() != input.len() + 1 {
+            return Err(VerifyError::WrongInputLength { expected: vk.ic.len().saturating_sub(1), got: input.len() });
+        }
+    }
+
+    let mut seed = env::random_seed();
+    seed.extend((proofs, inputs).try_to_vec().map_err(|_| VerifyError::SerializationError)?);
+    let r = (0..proofs.len() as u32).map(|i| {
+        let mut data = seed.clone();
+        data.extend(&i.to_le_bytes());
+        let mut bytes = env::keccak256(&data);
+        bytes[16..].iter_mut().for_each(|b| *b = 0);
+        U256::try_from_slice(&bytes).map_err(|_| VerifyError::SerializationError)
+    }).collect::<Result<Vec<_>, _>>()?;
+
+    let (alpha, neg_beta) = <(G1, G2)>::try_from_slice(&vk.alpha_neg_beta).map_err(|_| VerifyError::SerializationError)?;
+    let r_sum = r.iter().fold(U256::ZERO, |acc, &e| acc + e);
+
+    let mut acc_expr = vec![];
+    let mut pairing_expr = vec![];
+    for ((proof, input), &r_i) in proofs.iter().zip(inputs.iter()).zip(r.iter()) {
+        let acc_i = try_g1_multiexp(&vk.ic.iter().zip([U256::ONE].iter().chain(input.iter())).map(|(&base, &exp)| (base, exp)).collect::<Vec<_>>())?;
+        acc_expr.push((acc_i, r_i));
+        pairing_expr.push((try_g1_multiexp(&[(proof.a, r_i)])?, proof.b));
+    }
+    let c_expr = proofs.iter().zip(r.iter()).map(|(proof, &r_i)| (proof.c, r_i)).collect::<Vec<_>>();
+
+    pairing_expr.push((try_g1_multiexp(&[(alpha, r_sum)])?, neg_beta));
+    pairing_expr.push((try_g1_multiexp(&acc_expr)?, vk.neg_gamma));
+    pairing_expr.push((try_g1_multiexp(&c_expr)?, vk.neg_delta));
+
+    try_pairing_check(&pairing_expr)
+}
+
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prepared_pairing_input() {
+        let u = |v:u64| U256::from(v);
+        let vk = VK {
+            alpha: [u(1), u(2)],
+            beta: [u(3), u(4), u(5), u(6)],
+            gamma: [u(7), u(8), u(9), U256::ZERO],
+            delta: [u(10), u(11), u(12), u(13)],
+            ic: vec![[u(14), u(15)]]
+        };
+        let proof = Proof {
+            a: [u(16), u(17)],
+            b: [u(18), u(19), u(20), u(21)],
+            c: [u(22), u(23)]
+        };
+        let acc = [u(24), u(25)];
+        let pvk = PreparedVK::from(&vk);
+
+        let operands = vec![
+            (proof.a, proof.b),
+            (vk.alpha, g2_neg(vk.beta)),
+            (acc, g2_neg(vk.gamma)),
+            (proof.c, g2_neg(vk.delta)),
+        ];
+        let data = prepared_pairing_input(&pvk, &proof, acc).unwrap();
+        assert_eq!(data, operands.try_to_vec().unwrap());
+        println!("pairing operands: {}, cached serialized operands: 1", operands.len());
+
+        assert_eq!(g2_neg(vk.gamma)[3], U256::ZERO);
+        assert_eq!(g2_neg(vk.beta)[2] + u(5), FQ_MODULUS);
+    }
+
+    #[test]
+    fn test_u256_json() {
+        let modulus = "21888242871839275222246405745257275088696311157297823662689037894645226208583";
+        assert_eq!(serde_json::to_string(&FQ_MODULUS).unwrap(), format!("\"{}\"", modulus));
+        assert_eq!(serde_json::from_str::<U256>(&format!("\"{}\"", modulus)).unwrap(), FQ_MODULUS);
+        assert_eq!(serde_json::to_string(&U256::ZERO).unwrap(), "\"0\"");
+        assert_eq!(serde_json::from_str::<U256>("\"0\"").unwrap(), U256::ZERO);
+
+        for wrong in ["\"\"", "\"-1\"", "\"0x10\"", "1",
+            "\"115792089237316195423570985008687907853269984665640564039457584007913129639936\""].iter() {
+            assert!(serde_json::from_str::<U256>(wrong).is_err());
+        }
+    }
 }
@@ -19,7 +19,7 @@ use num::One;
 use crate::constants::{
     SEED_DECRYPTION_KEY, SEED_DIVERSIFIER, SEED_NOTE_HASH, SEED_NULLIFIER, SEED_TX_HASH,
 };
-use crate::native::tx::{Note, PoolParams, TransferPub, TransferSec, Tx, NOTE_CHUNKS};
+use crate::native::tx::{Note, PoolParams, TransferPub, TransferSec, Tx};
 
 #[derive(Clone, Signal)]
 #[Value = "Note<CS::F>"]
@@ -28,6 +28,7 @@ pub struct CNote<'a, CS: ConstraintSystem> {
     pub pk_d: CNum<'a, CS>,
     pub v: CNum<'a, CS>,
     pub st: CNum<'a, CS>,
+    pub asset: CNum<'a, CS>,
 }
 
 #[derive(Clone, Signal)]
@@ -37,7 +38,11 @@ pub struct CTransferPub<'a, CS: ConstraintSystem, P: PoolParams<Fr = CS::F>> {
     pub nullifier: SizedVec<CNum<'a, CS>, P::IN>,
     pub out_hash: SizedVec<CNum<'a, CS>, P::OUT>,
     pub delta: CNum<'a, CS>,
+    pub deposit_amount: CNum<'a, CS>,
+    pub withdraw_amount: CNum<'a, CS>,
+    pub fee: CNum<'a, CS>,
     pub memo: CNum<'a, CS>,
+    pub asset: CNum<'a, CS>,
 }
 
 #[derive(Clone, Signal)]
@@ -79,6 +84,7 @@ pub fn c_note_hash<'a, CS: ConstraintSystem, P: PoolParams<Fr = CS::F>>(
             note.pk_d.clone(),
             note.v.clone(),
             note.st.clone(),
+            note.asset.clone(),
         ]
         .as_ref(),
         SEED_NOTE_HASH,
@@ -145,9 +151,49 @@ pub fn c_derive_key_pk_d<'a, CS: ConstraintSystem, P: PoolParams<Fr = CS::F>>(
         .x
 }
 
-pub fn c_parse_delta<'a, CS: ConstraintSystem>(delta: &CNum<'a, CS>) -> CNum<'a, CS> {
-    let delta_bits = c_into_bits_le(delta, 64);
-    delta - &delta_bits[63].0 * num!(BigUint::one() << (NOTE_CHUNKS[2] * 8))
+// product of pairwise differences is nonzero, about n(n-1)/2 constraints for n items including the inverse
+// of assert_nonzero, 15 for IN = 6. A sorted witness needs a bit decomposition of each neighbour difference,
+// about 250 constraints per pair, see test_assert_unique, so the quadratic product is cheaper for the transaction sizes
+pub fn c_assert_unique<'a, CS: ConstraintSystem>(items: &[CNum<'a, CS>]) {
+    if items.len() < 2 {
+        return;
+    }
+    let mut acc = CNum::from_const(items[0].get_cs(), &Num::one());
+    for i in 0..items.len() {
+        for j in i + 1..items.len() {
+            acc *= &items[i] - &items[j];
+        }
+    }
+    acc.assert_nonzero();
+}
+
+// pk_d is the x coordinate of a point of the prime order subgroup other than the identity:
+// pk_d = (8*Q).x for a witness point Q on the curve, and pk_d != 0 excludes the small order points (0, 1) and (0, -1).
+// It takes a curve check of Q, three doublings and an inverse, at most 24 constraints per note (see test_check_pk_d).
+pub fn c_check_pk_d<'a, CS: ConstraintSystem, P: PoolParams<Fr = CS::F>>(pk_d: &CNum<'a, CS>, params: &P) {
+    CEdwardsPoint::subgroup_decompress(pk_d, params.jubjub());
+    pk_d.assert_nonzero();
+}
+
+// delta is a note_chunks()[2]*8-bit two's complement number, the same encoding as parse_delta
+// and the contract's parse_delta. Values out of range are rejected by the bit decomposition.
+pub fn c_parse_delta<'a, CS: ConstraintSystem, P: PoolParams<Fr = CS::F>>(delta: &CNum<'a, CS>, params: &P) -> CNum<'a, CS> {
+    let num_bits = params.note_chunks()[2] * 8;
+    let delta_bits = c_into_bits_le(delta, num_bits);
+    delta - &delta_bits[num_bits - 1].0 * num!(BigUint::one() << num_bits)
+}
+
+// 1 if a == b, 0 otherwise
+pub fn c_is_equal<'a, CS: ConstraintSystem>(a: &CNum<'a, CS>, b: &CNum<'a, CS>) -> CNum<'a, CS> {
+    let cs = a.get_cs();
+    let diff = a - b;
+    let inv_value = diff
+        .get_value()
+        .map(|v| if v == num!(0) { v } else { v.inverse() });
+    let inv = CNum::alloc(cs, inv_value.as_ref());
+    let res = CNum::from_const(cs, &Num::one()) - diff.clone() * &inv;
+    (diff * &res).assert_zero();
+    res
 }
 
 pub fn c_transfer<'a, CS: ConstraintSystem, P: PoolParams<Fr = CS::F>>(
@@ -155,13 +201,13 @@ pub fn c_transfer<'a, CS: ConstraintSystem, P: PoolParams<Fr = CS::F>>(
     s: &CTransferSec<'a, CS, P>,
     params: &P,
 ) {
-    let cs = p.get_cs();
-
     //check note value ranges
+    let note_chunks = params.note_chunks();
     for n in s.tx.input.iter().chain(s.tx.output.iter()) {
-        c_into_bits_le(&n.d, NOTE_CHUNKS[0] * 8);
-        c_into_bits_le(&n.v, NOTE_CHUNKS[2] * 8);
-        c_into_bits_le(&n.st, NOTE_CHUNKS[3] * 8);
+        c_into_bits_le(&n.d, note_chunks[0] * 8);
+        c_into_bits_le(&n.v, note_chunks[2] * 8);
+        c_into_bits_le(&n.st, note_chunks[3] * 8);
+        c_into_bits_le(&n.asset, note_chunks[4] * 8);
     }
 
     //build input hashes
@@ -179,28 +225,21 @@ pub fn c_transfer<'a, CS: ConstraintSystem, P: PoolParams<Fr = CS::F>>(
         (&s.tx.input[i].pk_d - c_derive_key_pk_d(&s.tx.input[i].d, &dk_bits, params)).assert_zero();
     }
 
+    //check output pk_d, input pk_d are derived from dk and are in the subgroup already
+    for n in s.tx.output.iter() {
+        c_check_pk_d(&n.pk_d, params);
+    }
+
     //check nullifier
     for i in 0..P::IN::USIZE {
         (&p.nullifier[i] - c_nullfifier(&in_hash[i], &s.eddsa_a, params)).assert_zero();
     }
 
     //check nullifier unique
-    let mut nullifier_unique_acc = CNum::from_const(cs, &Num::one());
-    for i in 0..P::IN::USIZE {
-        for j in i + 1..P::IN::USIZE {
-            nullifier_unique_acc *= &p.nullifier[i] - &p.nullifier[j];
-        }
-    }
-    nullifier_unique_acc.assert_nonzero();
+    c_assert_unique(&p.nullifier.0);
 
     //check output unique
-    let mut output_unique_acc = CNum::from_const(cs, &Num::one());
-    for i in 0..P::OUT::USIZE {
-        for j in i + 1..P::OUT::USIZE {
-            output_unique_acc *= &p.out_hash[i] - &p.out_hash[j];
-        }
-    }
-    output_unique_acc.assert_nonzero();
+    c_assert_unique(&p.out_hash.0);
 
     //build output hashes
     for i in 0..P::OUT::USIZE {
@@ -222,21 +261,36 @@ pub fn c_transfer<'a, CS: ConstraintSystem, P: PoolParams<Fr = CS::F>>(
     //check signature
     c_tx_verify(&s.eddsa_s, &s.eddsa_r, &s.eddsa_a, &tx_hash, params).assert_true();
 
+    //check relayer fee range
+    c_into_bits_le(&p.fee, note_chunks[2] * 8);
+
     //parse delta
-    let delta_amount = c_parse_delta(&p.delta);
+    let delta_amount = c_parse_delta(&p.delta, params);
 
-    //check balances
-    let mut amount = delta_amount;
+    //bind deposit and withdraw amounts to delta, at most one of them is nonzero
+    c_into_bits_le(&p.deposit_amount, note_chunks[2] * 8 - 1);
+    c_into_bits_le(&p.withdraw_amount, note_chunks[2] * 8);
+    (p.deposit_amount.clone() * &p.withdraw_amount).assert_zero();
+    (delta_amount.clone() - &p.deposit_amount + &p.withdraw_amount).assert_zero();
 
-    for note in s.tx.input.iter() {
-        amount += &note.v;
-    }
+    let delta_amount = delta_amount - &p.fee;
 
-    for note in s.tx.output.iter() {
-        amount -= &note.v;
-    }
+    //check balances per asset, delta and relayer fee are in p.asset, the fee is paid out of the pool
+    let assets = std::iter::once(&p.asset)
+        .chain(s.tx.input.iter().chain(s.tx.output.iter()).map(|n| &n.asset));
+    for asset in assets {
+        let mut amount = delta_amount.clone() * &c_is_equal(asset, &p.asset);
 
-    amount.assert_zero();
+        for note in s.tx.input.iter() {
+            amount += &(note.v.clone() * &c_is_equal(asset, &note.asset));
+        }
+
+        for note in s.tx.output.iter() {
+            amount -= &(note.v.clone() * &c_is_equal(asset, &note.asset));
+        }
+
+        amount.assert_zero();
+    }
 }
 
 
@@ -246,8 +300,137 @@ mod tx_test {
     use fawkes_crypto::core::cs::TestCS;
     use fawkes_crypto::native::bn256::Fr;
     use crate::{POOL_PARAMS};
+    use crate::native::tx::{derive_key_pk_d, parse_delta, NOTE_CHUNKS};
+    use fawkes_crypto::native::ecc::EdwardsPoint;
+    use rand::{thread_rng, Rng};
+    use crate::IN;
     use std::time::{Instant};
 
+    fn parse_delta_value(delta: Num<Fr>) -> Num<Fr> {
+        let ref mut cs = TestCS::<Fr>::new();
+        let ref delta = CNum::alloc(cs, Some(&delta));
+        c_parse_delta(delta, &*POOL_PARAMS).get_value().unwrap()
+    }
+
+    #[test]
+    fn test_parse_delta() {
+        let max_pos = num!((BigUint::one() << (NOTE_CHUNKS[2] * 8 - 1)) - BigUint::one());
+        let min_neg = num!(BigUint::one() << (NOTE_CHUNKS[2] * 8 - 1));
+        let minus_one = num!((BigUint::one() << (NOTE_CHUNKS[2] * 8)) - BigUint::one());
+
+        for &delta in [num!(0), num!(5), max_pos, min_neg, minus_one].iter() {
+            assert!(parse_delta_value(delta) == parse_delta(delta, &*POOL_PARAMS));
+        }
+        assert!(parse_delta_value(minus_one) == num!(0) - Num::<Fr>::one());
+        assert!(parse_delta_value(max_pos) + parse_delta_value(min_neg) == num!(0) - Num::<Fr>::one());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_parse_delta_above_max() {
+        // just above the largest 64-bit encoding
+        parse_delta_value(num!(BigUint::one() << (NOTE_CHUNKS[2] * 8)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_parse_delta_below_min() {
+        // the most negative amount minus one, encoded as a field element instead of two's complement
+        parse_delta_value(num!(0) - num!(BigUint::one() << (NOTE_CHUNKS[2] * 8 - 1)) - Num::one());
+    }
+
+    fn check_pk_d(pk_d: Num<Fr>) -> usize {
+        let ref mut cs = TestCS::<Fr>::new();
+        let ref pk_d = CNum::alloc(cs, Some(&pk_d));
+        let n_constraints = cs.num_constraints();
+        c_check_pk_d(pk_d, &*POOL_PARAMS);
+        cs.num_constraints() - n_constraints
+    }
+
+    #[test]
+    fn test_check_pk_d() {
+        let mut rng = thread_rng();
+        let pk_d = derive_key_pk_d(rng.gen(), rng.gen(), &*POOL_PARAMS).x;
+        let n_constraints = check_pk_d(pk_d);
+        println!("pk_d check constraints = {}", n_constraints);
+        assert!(n_constraints <= 24);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_check_pk_d_small_order() {
+        // x coordinate of the identity and of the point of order 2
+        check_pk_d(num!(0));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_check_pk_d_not_in_subgroup() {
+        let mut rng = thread_rng();
+        let pk_d = loop {
+            let x: Num<Fr> = rng.gen();
+            if EdwardsPoint::subgroup_decompress(x, POOL_PARAMS.jubjub()).is_none() {
+                break x;
+            }
+        };
+        check_pk_d(pk_d);
+    }
+
+    fn assert_unique(items: &[Num<Fr>]) -> usize {
+        let ref mut cs = TestCS::<Fr>::new();
+        let items = items.iter().map(|e| CNum::alloc(cs, Some(e))).collect::<Vec<_>>();
+        let n_constraints = cs.num_constraints();
+        c_assert_unique(&items);
+        cs.num_constraints() - n_constraints
+    }
+
+    // alternative to c_assert_unique measured by test_assert_unique: the items sorted off-circuit as a witness,
+    // a strict order check of the neighbours and a permutation check by the grand products at a challenge point
+    fn c_assert_unique_sorted<'a, CS: ConstraintSystem>(items: &[CNum<'a, CS>], sorted: &[CNum<'a, CS>], challenge: &CNum<'a, CS>) {
+        for i in 1..sorted.len() {
+            // sorted[i] - sorted[i-1] - 1 has no more than NUM_BITS - 1 bits, so sorted[i] > sorted[i-1]
+            c_into_bits_le(&(&sorted[i] - &sorted[i - 1] - Num::one()), Fr::NUM_BITS as usize - 1);
+        }
+        let mut acc_items = CNum::from_const(challenge.get_cs(), &Num::one());
+        let mut acc_sorted = CNum::from_const(challenge.get_cs(), &Num::one());
+        for (a, b) in items.iter().zip(sorted.iter()) {
+            acc_items *= challenge - a;
+            acc_sorted *= challenge - b;
+        }
+        (acc_items - acc_sorted).assert_zero();
+    }
+
+    fn assert_unique_sorted(items: &[Num<Fr>]) -> usize {
+        let ref mut cs = TestCS::<Fr>::new();
+        let mut sorted = items.to_vec();
+        sorted.sort_by_key(|&e| Into::<BigUint>::into(e));
+        let items = items.iter().map(|e| CNum::alloc(cs, Some(e))).collect::<Vec<_>>();
+        let sorted = sorted.iter().map(|e| CNum::alloc(cs, Some(e))).collect::<Vec<_>>();
+        let challenge = CNum::alloc(cs, Some(&num!(BigUint::from(0x5eed_u64))));
+        let n_constraints = cs.num_constraints();
+        c_assert_unique_sorted(&items, &sorted, &challenge);
+        cs.num_constraints() - n_constraints
+    }
+
+    #[test]
+    fn test_assert_unique() {
+        let items = (1..=IN::USIZE as u64).rev().map(|i| num!(BigUint::from(i))).collect::<Vec<_>>();
+        let n_constraints = assert_unique(&items);
+        let n_sorted_constraints = assert_unique_sorted(&items);
+        println!("nullifier uniqueness constraints: pairwise product = {}, sorted permutation = {}", n_constraints, n_sorted_constraints);
+        assert!(n_constraints <= IN::USIZE * (IN::USIZE - 1) / 2 + 1);
+        assert!(n_constraints < n_sorted_constraints);
+        assert_unique(&items[..1]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_assert_unique_duplicate() {
+        let mut items = (1..=IN::USIZE as u64).map(|i| num!(BigUint::from(i))).collect::<Vec<_>>();
+        items[IN::USIZE - 1] = items[0];
+        assert_unique(&items);
+    }
+
     #[test]
     fn test_circuit_tx() {
         let ref mut cs = TestCS::<Fr>::new();
@@ -3,3 +3,5 @@ pub const SEED_DECRYPTION_KEY: &'static [u8] = b"decryption_key";
 pub const SEED_TX_HASH: &'static [u8] = b"tx_hash";
 pub const SEED_NULLIFIER: &'static [u8] = b"nullifier";
 pub const SEED_NOTE_HASH: &'static [u8] = b"note";
+pub const SEED_WALLET: &'static [u8] = b"wallet";
+pub const SEED_NOTE_MAC: &'static [u8] = b"note_mac";
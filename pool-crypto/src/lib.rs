@@ -11,10 +11,14 @@ pub mod circuit;
 pub mod constants;
 pub mod native;
 
-use crate::native::tx::PoolBN256;
+use crate::native::tx::{PoolBN256, NOTE_CHUNKS};
 
 use pairing::bn256::Fr;
-use typenum::{Unsigned, U2, U32, U6};
+use typenum::{Unsigned, U2, U4, U6};
+#[cfg(not(feature = "small-tree"))]
+use typenum::U32;
+#[cfg(feature = "small-tree")]
+use typenum::U8;
 
 use fawkes_crypto::native::bn256::JubJubBN256;
 use fawkes_crypto::native::poseidon::PoseidonParams;
@@ -24,17 +28,37 @@ use std::marker::PhantomData;
 
 pub type IN = U6;
 pub type OUT = U2;
+#[cfg(not(feature = "small-tree"))]
 pub type H = U32;
+// shallow tree for faster tests, not compatible with the deployed contract
+#[cfg(feature = "small-tree")]
+pub type H = U8;
 pub type TPoolParams = PoolBN256::<IN, OUT, H>;
 
+// alternative configuration for 1-to-many transfers, up to 3 recipients and the change
+pub type OUT4 = U4;
+pub type TPoolParams4 = PoolBN256::<IN, OUT4, H>;
+
 lazy_static! {
     pub static ref POOL_PARAMS: TPoolParams = TPoolParams {
         jubjub: JubJubBN256::new(),
         hash: PoseidonParams::<Fr>::new(2, 8, 53),
         compress: PoseidonParams::<Fr>::new(3, 8, 53),
-        note: PoseidonParams::<Fr>::new(5, 8, 54),
+        note: PoseidonParams::<Fr>::new(6, 8, 54),
         tx: PoseidonParams::<Fr>::new(IN::USIZE + OUT::USIZE + 1, 8, 54),
         eddsa: PoseidonParams::<Fr>::new(4, 8, 53),
+        note_chunks: NOTE_CHUNKS,
+        phantom: PhantomData
+    };
+
+    pub static ref POOL_PARAMS4: TPoolParams4 = TPoolParams4 {
+        jubjub: JubJubBN256::new(),
+        hash: PoseidonParams::<Fr>::new(2, 8, 53),
+        compress: PoseidonParams::<Fr>::new(3, 8, 53),
+        note: PoseidonParams::<Fr>::new(6, 8, 54),
+        tx: PoseidonParams::<Fr>::new(IN::USIZE + OUT4::USIZE + 1, 8, 54),
+        eddsa: PoseidonParams::<Fr>::new(4, 8, 53),
+        note_chunks: NOTE_CHUNKS,
         phantom: PhantomData
     };
 }
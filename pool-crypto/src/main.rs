@@ -10,16 +10,22 @@ extern crate serde;
 pub mod circuit;
 pub mod constants;
 pub mod native;
+mod verifier;
+mod prover;
 
 use crate::native::data::gen_test_data;
 
 use crate::{
     circuit::tx::{c_transfer, CTransferPub, CTransferSec},
-    native::tx::{PoolBN256, TransferPub, TransferSec},
+    native::tx::{PoolBN256, TransferPub, TransferSec, NOTE_CHUNKS},
 };
 
 use pairing::bn256::Fr;
-use typenum::{Unsigned, U2, U32, U6};
+use typenum::{Unsigned, U2, U4, U6};
+#[cfg(not(feature = "small-tree"))]
+use typenum::U32;
+#[cfg(feature = "small-tree")]
+use typenum::U8;
 
 use fawkes_crypto::native::bn256::JubJubBN256;
 use fawkes_crypto::native::poseidon::PoseidonParams;
@@ -29,18 +35,38 @@ use std::marker::PhantomData;
 
 pub type IN = U6;
 pub type OUT = U2;
+#[cfg(not(feature = "small-tree"))]
 pub type H = U32;
+// shallow tree for faster tests, not compatible with the deployed contract
+#[cfg(feature = "small-tree")]
+pub type H = U8;
 
 pub type TPoolParams = PoolBN256::<IN, OUT, H>;
 
+// alternative configuration for 1-to-many transfers, up to 3 recipients and the change
+pub type OUT4 = U4;
+pub type TPoolParams4 = PoolBN256::<IN, OUT4, H>;
+
 lazy_static! {
     pub static ref POOL_PARAMS: TPoolParams = TPoolParams {
         jubjub: JubJubBN256::new(),
         hash: PoseidonParams::<Fr>::new(2, 8, 53),
         compress: PoseidonParams::<Fr>::new(3, 8, 53),
-        note: PoseidonParams::<Fr>::new(5, 8, 54),
+        note: PoseidonParams::<Fr>::new(6, 8, 54),
         tx: PoseidonParams::<Fr>::new(IN::USIZE + OUT::USIZE + 1, 8, 54),
         eddsa: PoseidonParams::<Fr>::new(4, 8, 53),
+        note_chunks: NOTE_CHUNKS,
+        phantom: PhantomData
+    };
+
+    pub static ref POOL_PARAMS4: TPoolParams4 = TPoolParams4 {
+        jubjub: JubJubBN256::new(),
+        hash: PoseidonParams::<Fr>::new(2, 8, 53),
+        compress: PoseidonParams::<Fr>::new(3, 8, 53),
+        note: PoseidonParams::<Fr>::new(6, 8, 54),
+        tx: PoseidonParams::<Fr>::new(IN::USIZE + OUT4::USIZE + 1, 8, 54),
+        eddsa: PoseidonParams::<Fr>::new(4, 8, 53),
+        note_chunks: NOTE_CHUNKS,
         phantom: PhantomData
     };
 }
@@ -57,6 +83,13 @@ groth16_near_bindings!(
     gen_test_data
 );
 
+// verify, export-vk and prove-from-json are handled here, the other commands by the cli of groth16_near_bindings
 fn main() {
-    cli::cli_main()
+    let args = std::env::args().collect::<Vec<_>>();
+    match args.get(1).map(String::as_str) {
+        Some("verify") => verifier::verify_main(&args[1..]),
+        Some("export-vk") => verifier::export_vk_main(&args[1..]),
+        Some("prove-from-json") => prover::prove_from_json_main(&args[1..]),
+        _ => cli::cli_main()
+    }
 }
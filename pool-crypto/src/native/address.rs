@@ -0,0 +1,197 @@
+use fawkes_crypto::borsh::{BorshDeserialize, BorshSerialize};
+use fawkes_crypto::core::field::Field;
+use fawkes_crypto::native::num::Num;
+
+use crate::native::tx::{to_compressed, to_decompressed, NOTE_CHUNKS};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+// human readable part of the encoded address
+pub const ADDRESS_HRP: &'static str = "zp";
+
+const CHARSET: &'static [u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+
+// Shielded address of the receiver: d (NOTE_CHUNKS[0] bytes) and pk_d (NOTE_CHUNKS[1] bytes)
+// packed into a single bech32 string with a checksum.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Address<F: Field> {
+    pub d: Num<F>,
+    pub pk_d: Num<F>,
+}
+
+impl<F: Field> From<(Num<F>, Num<F>)> for Address<F> {
+    fn from((d, pk_d): (Num<F>, Num<F>)) -> Self {
+        Self { d, pk_d }
+    }
+}
+
+impl<F: Field> From<Address<F>> for (Num<F>, Num<F>) {
+    fn from(addr: Address<F>) -> Self {
+        (addr.d, addr.pk_d)
+    }
+}
+
+impl<F: Field> Address<F> {
+    fn to_bytes(&self) -> Result<Vec<u8>, io::Error> {
+        let num_size = (F::NUM_BITS as usize - 1) / 8 + 1;
+        let mut buf = self.d.try_to_vec()?;
+        self.pk_d.serialize(&mut buf)?;
+        to_compressed(&buf, num_size, &NOTE_CHUNKS[0..2])
+    }
+
+    fn from_bytes(data: &[u8]) -> Result<Self, io::Error> {
+        let num_size = (F::NUM_BITS as usize - 1) / 8 + 1;
+        let buf = to_decompressed(data, num_size, &NOTE_CHUNKS[0..2])?;
+        let mut r = &buf[..];
+        Ok(Self {
+            d: Num::deserialize(&mut r)?,
+            pk_d: Num::deserialize(&mut r)?,
+        })
+    }
+}
+
+// d wider than NOTE_CHUNKS[0] bytes can't be encoded, to_string panics in this case
+impl<F: Field> fmt::Display for Address<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let data = convert_bits(&self.to_bytes().map_err(|_| fmt::Error)?, 8, 5, true)
+            .ok_or(fmt::Error)?;
+        let checksum = create_checksum(ADDRESS_HRP.as_bytes(), &data);
+        let s = data.iter().chain(checksum.iter())
+            .map(|&e| CHARSET[e as usize] as char)
+            .collect::<String>();
+        write!(f, "{}1{}", ADDRESS_HRP, s)
+    }
+}
+
+impl<F: Field> FromStr for Address<F> {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        if s.chars().any(|c| c.is_ascii_lowercase()) && s.chars().any(|c| c.is_ascii_uppercase()) {
+            return Err(err("Mixed case address"));
+        }
+        let s = s.to_ascii_lowercase();
+        let pos = s.rfind('1').ok_or_else(|| err("Wrong address format"))?;
+        if &s[..pos] != ADDRESS_HRP {
+            return Err(err("Wrong address prefix"));
+        }
+        let values = s[pos + 1..].bytes()
+            .map(|c| CHARSET.iter().position(|&e| e == c).map(|e| e as u8))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| err("Wrong address character"))?;
+        if values.len() < 6 || !verify_checksum(ADDRESS_HRP.as_bytes(), &values) {
+            return Err(err("Wrong address checksum"));
+        }
+        let data = convert_bits(&values[..values.len() - 6], 5, 8, false)
+            .ok_or_else(|| err("Wrong address padding"))?;
+        Self::from_bytes(&data)
+    }
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    let mut chk = 1u32;
+    for &v in values {
+        let b = chk >> 25;
+        chk = (chk & 0x1ffffff) << 5 ^ v as u32;
+        for (i, g) in GENERATOR.iter().enumerate() {
+            if (b >> i) & 1 == 1 {
+                chk ^= g;
+            }
+        }
+    }
+    chk
+}
+
+fn hrp_expand(hrp: &[u8]) -> Vec<u8> {
+    hrp.iter().map(|&c| c >> 5)
+        .chain(std::iter::once(0))
+        .chain(hrp.iter().map(|&c| c & 31))
+        .collect()
+}
+
+fn create_checksum(hrp: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut values = hrp_expand(hrp);
+    values.extend_from_slice(data);
+    values.extend_from_slice(&[0; 6]);
+    let pm = polymod(&values) ^ 1;
+    (0..6).map(|i| ((pm >> (5 * (5 - i))) & 31) as u8).collect()
+}
+
+fn verify_checksum(hrp: &[u8], data: &[u8]) -> bool {
+    let mut values = hrp_expand(hrp);
+    values.extend_from_slice(data);
+    polymod(&values) == 1
+}
+
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let mut acc = 0u32;
+    let mut bits = 0u32;
+    let mut res = vec![];
+    let maxv = (1u32 << to) - 1;
+    for &v in data {
+        if (v as u32) >> from != 0 {
+            return None;
+        }
+        acc = (acc << from) | v as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            res.push(((acc >> bits) & maxv) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            res.push(((acc << (to - bits)) & maxv) as u8);
+        }
+    } else if bits >= from || ((acc << (to - bits)) & maxv) != 0 {
+        return None;
+    }
+    Some(res)
+}
+
+#[cfg(test)]
+mod address_test {
+    use super::*;
+    use crate::native::data::rand_biguint;
+    use crate::native::tx::derive_key_pk_d;
+    use crate::POOL_PARAMS;
+    use fawkes_crypto::native::bn256::Fr;
+    use rand::{thread_rng, Rng};
+
+    fn rand_address() -> Address<Fr> {
+        let mut rng = thread_rng();
+        let d = num!(rand_biguint(&mut rng, NOTE_CHUNKS[0] * 8));
+        let pk_d = derive_key_pk_d(d, rng.gen(), &*POOL_PARAMS).x;
+        Address { d, pk_d }
+    }
+
+    #[test]
+    fn test_address_roundtrip() {
+        let addr = rand_address();
+        let s = addr.to_string();
+        assert!(s.starts_with("zp1"));
+        assert!(s.parse::<Address<Fr>>().unwrap() == addr);
+        assert!(s.to_uppercase().parse::<Address<Fr>>().unwrap() == addr);
+    }
+
+    #[test]
+    fn test_address_corruption() {
+        let s = rand_address().to_string();
+        let bytes = s.as_bytes();
+
+        for i in ADDRESS_HRP.len() + 1..bytes.len() {
+            let mut corrupted = bytes.to_vec();
+            corrupted[i] = if bytes[i] == b'q' { b'p' } else { b'q' };
+            let corrupted = String::from_utf8(corrupted).unwrap();
+            assert!(corrupted.parse::<Address<Fr>>().is_err());
+        }
+
+        assert!(s.replacen("zp1", "zq1", 1).parse::<Address<Fr>>().is_err());
+        assert!(s[..s.len() - 1].parse::<Address<Fr>>().is_err());
+        assert!(format!("{}{}", &s[..3].to_uppercase(), &s[3..]).parse::<Address<Fr>>().is_err());
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
     native::tx::{
-        note_encrypt, note_hash, nullfifier, tx_hash, Note, PoolBN256, PoolParams, TransferPub,
+        encrypted_note_size, note_decrypt_in, note_encrypt, EncryptError, note_hash, nullfifier, scan_notes, tx_hash, Note, PoolBN256, PoolParams, TransferPub,
         TransferSec, Tx,
     },
 };
@@ -14,19 +14,27 @@ use num::{One, Zero};
 
 use fawkes_crypto::core::sizedvec::SizedVec;
 use fawkes_crypto::native::num::Num;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::marker::PhantomData;
 use typenum::Unsigned;
 
 
 use fawkes_crypto::borsh::{BorshDeserialize, BorshSerialize};
 use fawkes_crypto::native::eddsaposeidon::eddsaposeidon_sign;
-use fawkes_crypto::native::poseidon::{poseidon, MerkleProof};
+use fawkes_crypto::native::poseidon::{poseidon, poseidon_merkle_proof_root, MerkleProof, PoseidonParams};
+use fawkes_crypto::core::field::PrimeField;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
 use sha3::{Digest, Keccak256};
+use bip39::{Language, Mnemonic};
 
 use kvdb::{KeyValueDB, DBTransaction};
 
 
-use crate::native::tx::{derive_key_dk, derive_key_xsk, derive_key_pk_d, NOTE_CHUNKS};
+use crate::native::tx::{derive_key_dk, derive_key_xsk, derive_key_pk_d, default_asset, NOTE_CHUNKS};
+use crate::constants::SEED_WALLET;
 
 
 use kvdb_memorydb::{self, InMemory};
@@ -46,8 +54,8 @@ pub fn rand_biguint<R: Rng>(rng: &mut R, bits: usize) -> BigUint {
     BigUint::from_bytes_be(&v)
 }
 
-pub fn prepare_delta<F: Field>(mut delta: BigInt) -> Num<F> {
-    let limit_amount = BigInt::one() << (NOTE_CHUNKS[2] * 8);
+pub fn prepare_delta<P: PoolParams>(mut delta: BigInt, params: &P) -> Num<P::Fr> {
+    let limit_amount = BigInt::one() << (params.note_chunks()[2] * 8);
 
     if delta.sign() == Sign::Minus {
         delta += &limit_amount;
@@ -57,15 +65,272 @@ pub fn prepare_delta<F: Field>(mut delta: BigInt) -> Num<F> {
     num!(delta.to_biguint().unwrap())
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum TxBuildError {
+    // value of the best selected notes is less than the value to be covered by notes
+    InsufficientFunds { available: BigUint, required: BigUint },
+    // there are no notes to spend, while the transaction needs some
+    NoNotes,
+    // delta does not fit into note_chunks()[2] bytes two's complement
+    DeltaOutOfRange,
+    // explicitly selected note does not exist, is spent or pending
+    UnknownNote(usize),
+    // explicitly selected note is listed twice
+    DuplicateNote(usize),
+    // more explicitly selected notes than P::IN
+    TooManyInputs,
+    // wallet database error, see StateError
+    State(String),
+    // the state is opened with a ViewingKey
+    ViewOnly,
+    // explicitly selected notes are of different assets
+    MixedAssets,
+    // recipient pk_d is not a valid point, see EncryptError
+    InvalidRecipient,
+}
+
+#[derive(Debug)]
+pub enum StateError {
+    // kvdb read or write failed
+    Db(io::Error),
+    // stored value can't be decoded
+    Corrupted(io::Error),
+    // rescan got other than P::OUT out_hashes per message
+    OutHashCount { messages: usize, out_hashes: usize },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MnemonicError {
+    // not a BIP39 english phrase: word count, unknown word or checksum
+    InvalidPhrase(bip39::Error),
+    // entropy is not 16 to 32 bytes, a multiple of 4 bytes
+    InvalidEntropy(bip39::Error),
+}
+
+impl From<io::Error> for StateError {
+    fn from(e: io::Error) -> Self {
+        StateError::Db(e)
+    }
+}
+
+impl From<StateError> for TxBuildError {
+    fn from(e: StateError) -> Self {
+        TxBuildError::State(format!("{:?}", e))
+    }
+}
+
+impl From<EncryptError> for TxBuildError {
+    fn from(e: EncryptError) -> Self {
+        match e {
+            EncryptError::InvalidRecipient => TxBuildError::InvalidRecipient,
+        }
+    }
+}
+
+fn decode<T: BorshDeserialize>(v: &[u8]) -> Result<T, StateError> {
+    T::try_from_slice(v).map_err(StateError::Corrupted)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelectionStrategy {
+    // up to P::IN largest notes, then the smallest ones are swapped in while the amount is covered
+    MinimizeChange,
+    // as many of the smallest notes as possible, the largest ones are taken only to cover the amount
+    ConsolidateSmallest,
+    // the fewest largest notes covering the amount
+    ExactLargest,
+    // branch and bound search for the smallest change, falls back to ExactLargest
+    BranchAndBound,
+}
+
+// limit of visited nodes in the branch and bound search
+const MAX_BNB_TRIES: usize = 100_000;
+
+struct BnbSearch<'a> {
+    values: &'a [BigInt],
+    // prefix[i] is the sum of values[..i]
+    prefix: Vec<BigInt>,
+    need: &'a BigInt,
+    max_inputs: usize,
+    tries: usize,
+    selected: Vec<usize>,
+    best: Option<(BigInt, Vec<usize>)>,
+}
+
+impl<'a> BnbSearch<'a> {
+    fn search(&mut self, pos: usize, sum: BigInt) {
+        if self.tries == 0 {
+            return;
+        }
+        self.tries -= 1;
+
+        if sum >= *self.need {
+            let better = match self.best.as_ref() {
+                Some((best_sum, best)) => sum < *best_sum || sum == *best_sum && self.selected.len() < best.len(),
+                None => true,
+            };
+            if better {
+                self.best = Some((sum, self.selected.clone()));
+            }
+            return;
+        }
+
+        if self.best.as_ref().map_or(false, |b| b.0 == *self.need) {
+            return;
+        }
+
+        let len = self.values.len();
+        if self.selected.len() == self.max_inputs || pos == len {
+            return;
+        }
+
+        // values are sorted in descending order, so the next ones give the largest reachable sum
+        let last = std::cmp::min(pos + self.max_inputs - self.selected.len(), len);
+        if &sum + &self.prefix[last] - &self.prefix[pos] < *self.need {
+            return;
+        }
+
+        self.selected.push(pos);
+        self.search(pos + 1, &sum + &self.values[pos]);
+        self.selected.pop();
+
+        // skipping a value skips its duplicates, they give the same subsets
+        let mut next = pos + 1;
+        while next < len && self.values[next] == self.values[pos] {
+            next += 1;
+        }
+        self.search(next, sum);
+    }
+}
+
+// values are sorted in descending order, returns indexes of the values to spend, their sum covers need
+// if the first max_inputs values do
+fn select_notes(values: &[BigInt], need: &BigInt, max_inputs: usize, strategy: SelectionStrategy) -> Vec<usize> {
+    let n = std::cmp::min(max_inputs, values.len());
+    let sum = |indexes: &[usize]| -> BigInt { indexes.iter().map(|&i| values[i].clone()).sum() };
+
+    match strategy {
+        SelectionStrategy::MinimizeChange => {
+            let mut indexes: Vec<usize> = (0..n).collect();
+            let mut spending = sum(&indexes);
+            if values.len() > max_inputs {
+                for i in 0..max_inputs {
+                    let i1 = max_inputs - i - 1;
+                    let i2 = values.len() - i - 1;
+
+                    let delta = &values[i1] - &values[i2];
+                    if &spending - &delta < *need {
+                        break;
+                    }
+                    spending -= delta;
+                    indexes[i1] = i2;
+                }
+            }
+            indexes
+        }
+        SelectionStrategy::ConsolidateSmallest => {
+            // k largest notes and n-k smallest ones, the least k covering need
+            (0..=n)
+                .map(|k| (0..k).chain(values.len() - (n - k)..values.len()).collect::<Vec<_>>())
+                .find(|indexes| sum(indexes) >= *need)
+                .unwrap_or_else(|| (0..n).collect())
+        }
+        SelectionStrategy::ExactLargest => {
+            let mut spending = BigInt::zero();
+            let mut k = 0;
+            while k < n && spending < *need {
+                spending += &values[k];
+                k += 1;
+            }
+            (0..k).collect()
+        }
+        SelectionStrategy::BranchAndBound => {
+            let mut prefix = vec![BigInt::zero()];
+            for v in values.iter() {
+                let last = prefix[prefix.len() - 1].clone();
+                prefix.push(last + v);
+            }
+
+            let mut search = BnbSearch {
+                values,
+                prefix,
+                need,
+                max_inputs,
+                tries: MAX_BNB_TRIES,
+                selected: vec![],
+                best: None,
+            };
+            search.search(0, BigInt::zero());
+
+            match search.best {
+                Some((_, indexes)) => indexes,
+                None => select_notes(values, need, max_inputs, SelectionStrategy::ExactLargest),
+            }
+        }
+    }
+}
+
 pub trait Wallet<P:PoolParams> {
     fn xsk(&self, params:&P) -> Num<P::Fr>;
     fn sign(&self, msg:Num<P::Fr>, params:&P) -> (Num<P::Fs>, Num<P::Fr>);
+
+    fn can_sign(&self) -> bool {
+        true
+    }
 }
 
 pub struct NativeWallet<P:PoolParams> {
     pub sk: Num<P::Fs>
 }
 
+impl<P:PoolParams> NativeWallet<P> {
+    // sk = keccak256(SEED_WALLET || seed || counter) cut to the bit length of Fs,
+    // the least counter giving a nonzero value below the modulus is taken
+    pub fn from_seed(seed: &[u8]) -> Self {
+        let num_bits = P::Fs::NUM_BITS as usize;
+        for counter in 0u32.. {
+            let mut h = Keccak256::new();
+            h.update(SEED_WALLET);
+            h.update(seed);
+            h.update(counter.to_be_bytes());
+            let mut hash = h.finalize();
+            if num_bits < 256 {
+                hash[0] &= 0xffu8 >> (256 - num_bits);
+            }
+
+            let value = BigUint::from_bytes_be(&hash);
+            let sk: Num<P::Fs> = Num::from_binary_be(&hash);
+            if sk != num!(0) && Into::<BigUint>::into(sk) == value {
+                return Self { sk };
+            }
+        }
+        unreachable!()
+    }
+
+    // BIP39 phrase of 12 to 24 words of the english wordlist with a valid checksum, case and whitespace
+    // are normalized. sk is from_seed of the BIP39 seed with an empty passphrase, the same seed
+    // other BIP39 tools derive from the phrase
+    pub fn from_mnemonic(phrase: &str) -> Result<Self, MnemonicError> {
+        let words = phrase.split_whitespace().map(|w| w.to_lowercase()).collect::<Vec<_>>().join(" ");
+        let mnemonic = Mnemonic::parse_in_normalized(Language::English, &words)
+            .map_err(MnemonicError::InvalidPhrase)?;
+        Ok(Self::from_seed(&mnemonic.to_seed("")))
+    }
+}
+
+// BIP39 english phrase of 16 to 32 bytes of entropy, a multiple of 4 bytes
+pub fn to_mnemonic(entropy: &[u8]) -> Result<String, MnemonicError> {
+    Mnemonic::from_entropy_in(Language::English, entropy)
+        .map(|m| m.to_string())
+        .map_err(MnemonicError::InvalidEntropy)
+}
+
+// random 12 words phrase for NativeWallet::from_mnemonic
+pub fn generate_mnemonic<R: Rng>(rng: &mut R) -> String {
+    let entropy: [u8; 16] = rng.gen();
+    to_mnemonic(&entropy).unwrap()
+}
+
 impl<P:PoolParams> Wallet<P> for NativeWallet<P> {
     fn xsk(&self, params: &P) -> Num<P::Fr> {
         derive_key_xsk(self.sk, params).x
@@ -76,6 +341,126 @@ impl<P:PoolParams> Wallet<P> for NativeWallet<P> {
     }
 }
 
+impl<P:PoolParams> NativeWallet<P> {
+    pub fn viewing_key(&self, params: &P) -> ViewingKey<P> {
+        let xsk = self.xsk(params);
+        ViewingKey { xsk, dk: derive_key_dk(xsk, params) }
+    }
+}
+
+// Watch-only key material: enough to decrypt notes and compute nullifiers, but not to sign
+pub struct ViewingKey<P:PoolParams> {
+    pub xsk: Num<P::Fr>,
+    pub dk: Num<P::Fs>,
+}
+
+impl<P:PoolParams> Wallet<P> for ViewingKey<P> {
+    fn xsk(&self, _params: &P) -> Num<P::Fr> {
+        self.xsk
+    }
+
+    fn sign(&self, _msg:Num<P::Fr>, _params:&P) -> (Num<P::Fs>, Num<P::Fr>) {
+        panic!("view-only wallet can't sign")
+    }
+
+    fn can_sign(&self) -> bool {
+        false
+    }
+}
+
+// values of the empty tree cells by level, from the zero leaf up to the root
+pub fn default_cell_values<P: PoolParams>(params: &P) -> Vec<Num<P::Fr>> {
+    let mut res: Vec<Num<P::Fr>> = vec![num!(0); P::H::USIZE + 1];
+    for i in 0..P::H::USIZE {
+        let c = res[i];
+        res[i + 1] = poseidon(&[c, c], params.compress());
+    }
+    res
+}
+
+// parent cells of a tree level, a missing right sibling of the last cell is the default cell of the level
+fn hash_level_seq<F: PrimeField>(level: &[Num<F>], default_cell: Num<F>, compress: &PoseidonParams<F>) -> Vec<Num<F>> {
+    level.chunks(2)
+        .map(|c| poseidon(&[c[0], c.get(1).cloned().unwrap_or(default_cell)], compress))
+        .collect()
+}
+
+// hash_level_seq over disjoint pairs of cells in parallel
+#[cfg(feature = "rayon")]
+fn hash_level<F: PrimeField>(level: &[Num<F>], default_cell: Num<F>, compress: &PoseidonParams<F>) -> Vec<Num<F>> {
+    level.par_chunks(2)
+        .map(|c| poseidon(&[c[0], c.get(1).cloned().unwrap_or(default_cell)], compress))
+        .collect()
+}
+
+#[cfg(not(feature = "rayon"))]
+fn hash_level<F: PrimeField>(level: &[Num<F>], default_cell: Num<F>, compress: &PoseidonParams<F>) -> Vec<Num<F>> {
+    hash_level_seq(level, default_cell, compress)
+}
+
+// Cells of the merkle tree with the given leaves at positions 0..leaves.len(), levels 0..=H where
+// H = default_cells.len() - 1. Only the nonempty prefix of each level is returned, the rest are default cells.
+// Levels are hashed in parallel with the rayon feature, the wasm build stays single-threaded.
+pub fn rebuild_tree<F: PrimeField>(leaves: &[Num<F>], default_cells: &[Num<F>], compress: &PoseidonParams<F>) -> Vec<Vec<Num<F>>> {
+    let mut res = vec![leaves.to_vec()];
+    for i in 0..default_cells.len() - 1 {
+        let level = hash_level(&res[i], default_cells[i], compress);
+        res.push(level);
+    }
+    res
+}
+
+// root of the merkle tree of height P::H with all leaves zero
+pub fn empty_tree_root<P: PoolParams>(params: &P) -> Num<P::Fr> {
+    default_cell_values(params)[P::H::USIZE]
+}
+
+// max number of merkle tree cells kept in memory by ClientState
+const CELL_CACHE_SIZE: usize = 1 << 14;
+
+// LRU cache of merkle tree cells keyed by (level, index), the least recently used half
+// is dropped when it is full
+struct CellCache<F: Field> {
+    cells: HashMap<(usize, usize), (Num<F>, u64)>,
+    tick: u64,
+    // number of cells read from the db
+    misses: usize,
+}
+
+impl<F: Field> CellCache<F> {
+    fn new() -> Self {
+        Self {
+            cells: HashMap::new(),
+            tick: 0,
+            misses: 0,
+        }
+    }
+
+    fn get(&mut self, pos: (usize, usize)) -> Option<Num<F>> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.cells.get_mut(&pos).map(|e| {
+            e.1 = tick;
+            e.0
+        })
+    }
+
+    fn insert(&mut self, pos: (usize, usize), v: Num<F>) {
+        self.misses += 1;
+        if self.cells.len() >= CELL_CACHE_SIZE {
+            let mut ticks = self.cells.values().map(|e| e.1).collect::<Vec<_>>();
+            ticks.sort_unstable();
+            let min_tick = ticks[ticks.len() / 2];
+            self.cells.retain(|_, e| e.1 >= min_tick);
+        }
+        self.cells.insert(pos, (v, self.tick));
+    }
+
+    fn remove(&mut self, pos: (usize, usize)) {
+        self.cells.remove(&pos);
+    }
+}
+
 pub struct ClientState<'p, 'db, 'w, P: PoolParams, DB:KeyValueDB, W:Wallet<P>> {
     pub db: &'db DB,
     pub wallet: &'w W,
@@ -83,6 +468,7 @@ pub struct ClientState<'p, 'db, 'w, P: PoolParams, DB:KeyValueDB, W:Wallet<P>> {
     pub xsk: Num<P::Fr>,
     pub default_cell_value: Vec<Num<P::Fr>>,
     pub params: &'p P,
+    cell_cache: RefCell<CellCache<P::Fr>>,
 }
 
 const KEY_INITIALIZED: &[u8] = b"initialized";
@@ -91,45 +477,131 @@ const KEY_NUM_LEAVES: &[u8] = b"num_leaves";
 const COL_DEFAULT: u32 = 0;
 const COL_CELL: u32 = 1;
 const COL_NULLIFIER: u32 = 2;
-const COL_NOTE: u32 = 2;
+// databases created before notes got their own column keep notes in column 2
+// and should be resynced from the contract
+const COL_NOTE: u32 = 3;
+// nullifiers of notes spent by transactions that are not confirmed yet
+const COL_PENDING: u32 = 4;
+
+const NUM_COLS: usize = 5;
+
+// wallet backup, raw key-value pairs of the columns sorted by key
+#[derive(BorshSerialize, BorshDeserialize)]
+struct StateExport {
+    num_leaves: u64,
+    cells: Vec<(Vec<u8>, Vec<u8>)>,
+    notes: Vec<(Vec<u8>, Vec<u8>)>,
+    nullifiers: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+
+// ClientState on disk. ClientState borrows its db, so the wrapper owns the rocksdb database
+// and lends a ClientState over it with state(), which picks up the tree and the notes of an existing db
+#[cfg(feature = "rocksdb")]
+pub struct PersistentClientState<'p, 'w, P: PoolParams, W: Wallet<P>> {
+    pub db: kvdb_rocksdb::Database,
+    pub wallet: &'w W,
+    pub params: &'p P,
+}
 
-const NUM_COLS: usize = 4;
+#[cfg(feature = "rocksdb")]
+impl<'p, 'w, P: PoolParams, W: Wallet<P>> PersistentClientState<'p, 'w, P, W> {
+    // opens or creates the db at path with the column layout of ClientState
+    pub fn open_rocksdb(path: &str, wallet: &'w W, params: &'p P) -> Result<Self, StateError> {
+        let db = kvdb_rocksdb::Database::open(&kvdb_rocksdb::DatabaseConfig::with_columns(NUM_COLS as u32), path)?;
+        let res = Self { db, wallet, params };
+        res.state()?;
+        Ok(res)
+    }
 
+    pub fn state(&self) -> Result<ClientState<'p, '_, 'w, P, kvdb_rocksdb::Database, W>, StateError> {
+        ClientState::new(&self.db, self.wallet, self.params)
+    }
+}
 
 impl<'p, 'db, 'w, P: PoolParams, DB:KeyValueDB, W:Wallet<P>> ClientState<'p, 'db, 'w, P, DB, W> {
-    pub fn new(db: &'db DB, wallet: &'w W, params: &'p P) -> Self {
-        if db.get(COL_DEFAULT, KEY_INITIALIZED).unwrap().is_none() {
+    pub fn new(db: &'db DB, wallet: &'w W, params: &'p P) -> Result<Self, StateError> {
+        if db.get(COL_DEFAULT, KEY_INITIALIZED)?.is_none() {
             let mut tx = DBTransaction::new();
             tx.put(COL_DEFAULT, KEY_INITIALIZED, &[1u8]);
             tx.put(COL_DEFAULT, KEY_NUM_LEAVES, &0u64.try_to_vec().unwrap());
-            db.write(tx).unwrap();
+            db.write(tx)?;
         }
 
         let xsk = wallet.xsk(params);
         let dk = derive_key_dk(xsk, params);
 
-        let mut default_cell_value: Vec<Num<P::Fr>> = vec![num!(0); H::USIZE + 1];
-        for i in 0..H::USIZE {
-            let c = default_cell_value[i];
-            default_cell_value[i + 1] = poseidon(&[c, c], params.compress());
-        }
+        let default_cell_value = default_cell_values(params);
 
-        Self {
+        Ok(Self {
             db,
             wallet,
             dk,
             xsk,
             default_cell_value,
             params,
+            cell_cache: RefCell::new(CellCache::new()),
+        })
+    }
+
+
+    // imports a backup made by export_state into a fresh db
+    pub fn import_state(db: &'db DB, wallet: &'w W, params: &'p P, data: &[u8]) -> Result<Self, StateError> {
+        let state: StateExport = decode(data)?;
+        let mut tx = DBTransaction::new();
+        tx.put(COL_DEFAULT, KEY_INITIALIZED, &[1u8]);
+        tx.put(COL_DEFAULT, KEY_NUM_LEAVES, &state.num_leaves.try_to_vec().unwrap());
+        for (col, items) in [(COL_CELL, state.cells), (COL_NOTE, state.notes), (COL_NULLIFIER, state.nullifiers)].iter() {
+            for (k, v) in items.iter() {
+                tx.put(*col, k, v);
+            }
+        }
+        db.write(tx)?;
+        Self::new(db, wallet, params)
+    }
+
+    // wipes all columns and starts with an empty tree, as a freshly created state
+    pub fn reset(&self) -> Result<(), StateError> {
+        let mut tx = DBTransaction::new();
+        for col in 0..NUM_COLS as u32 {
+            for (k, _) in self.db.iter(col) {
+                tx.delete(col, &k);
+            }
         }
+        tx.put(COL_DEFAULT, KEY_INITIALIZED, &[1u8]);
+        tx.put(COL_DEFAULT, KEY_NUM_LEAVES, &0u64.try_to_vec().unwrap());
+        *self.cell_cache.borrow_mut() = CellCache::new();
+        Ok(self.db.write(tx)?)
     }
 
+    pub fn export_state(&self) -> Result<Vec<u8>, StateError> {
+        let column = |col: u32| {
+            let mut items: Vec<(Vec<u8>, Vec<u8>)> = self.db.iter(col)
+                .map(|(k, v)| (k.to_vec(), v.to_vec()))
+                .collect();
+            items.sort();
+            items
+        };
+
+        Ok(StateExport {
+            num_leaves: self.gen_num_leaves()? as u64,
+            cells: column(COL_CELL),
+            notes: column(COL_NOTE),
+            nullifiers: column(COL_NULLIFIER),
+        }.try_to_vec().unwrap())
+    }
 
-    fn get_cell(&self, pos: (usize, usize)) -> Num<P::Fr> {
+    fn get_cell(&self, pos: (usize, usize)) -> Result<Num<P::Fr>, StateError> {
+        if let Some(v) = self.cell_cache.borrow_mut().get(pos) {
+            return Ok(v);
+        }
         let key = (pos.0 as u64, pos.1 as u64).try_to_vec().unwrap();
-        self.db.get(COL_CELL, &key).unwrap()
-            .map(|v| <Num<P::Fr>>::try_from_slice(&v).unwrap())
-            .unwrap_or(self.default_cell_value[pos.0])
+        let v = match self.db.get(COL_CELL, &key)? {
+            Some(v) => decode(&v)?,
+            None => self.default_cell_value[pos.0]
+        };
+        self.cell_cache.borrow_mut().insert(pos, v);
+        Ok(v)
     }
 
     fn set_cell(&self, tx: &mut DBTransaction, pos: (usize, usize), v: Num<P::Fr>) {
@@ -138,249 +610,677 @@ impl<'p, 'db, 'w, P: PoolParams, DB:KeyValueDB, W:Wallet<P>> ClientState<'p, 'db
     }
 
 
-    fn get_note(&self, pos: usize) -> Option<Note<P::Fr>> {
+    fn get_note(&self, pos: usize) -> Result<Option<Note<P::Fr>>, StateError> {
         let key = (pos as u64).try_to_vec().unwrap();
-        self.db.get(COL_NOTE, &key).unwrap()
-            .map(|v| <Note<P::Fr>>::try_from_slice(&v).unwrap())
+        match self.db.get(COL_NOTE, &key)? {
+            Some(v) => self.decode_note(&v).map(Some),
+            None => Ok(None)
+        }
     }
 
     fn set_note(&self, tx: &mut DBTransaction, pos: usize, v: Note<P::Fr>) {
         let key = (pos as u64).try_to_vec().unwrap();
-        tx.put(COL_NOTE, &key, &v.try_to_vec().unwrap());
+        tx.put(COL_NOTE, &key, &v.to_vec_with_chunks(self.params.note_chunks()).unwrap());
+    }
+
+    fn decode_note(&self, v: &[u8]) -> Result<Note<P::Fr>, StateError> {
+        Note::from_slice_with_chunks(v, self.params.note_chunks()).map_err(StateError::Corrupted)
+    }
+
+    // the nullifier the contract records when the note is spent, see is_nullifier
+    pub fn note_nullifier(&self, note: Note<P::Fr>) -> Num<P::Fr> {
+        nullfifier(note_hash(note, self.params), self.xsk, self.params)
+    }
+
+    fn is_nullified(&self, pos: usize) -> Result<bool, StateError> {
+        let key = (pos as u64).try_to_vec().unwrap();
+        Ok(self.db.get(COL_NULLIFIER, &key)?.is_some())
+    }
+
+    // marks the note at pos as spent by a confirmed transaction
+    pub fn nullify_note(&self, pos: usize) -> Result<(), StateError> {
+        let note = match self.get_note(pos)? {
+            Some(note) => note,
+            None => return Ok(())
+        };
+        let key = (pos as u64).try_to_vec().unwrap();
+        let nullifier = self.note_nullifier(note);
+        let mut tx = DBTransaction::new();
+        tx.put(COL_NULLIFIER, &key, &nullifier.try_to_vec().unwrap());
+        Ok(self.db.write(tx)?)
+    }
+
+    // deletes the note record of a spent note, the tree cell stays for merkle proofs
+    pub fn remove_note(&self, pos: usize) -> Result<(), StateError> {
+        let note = match self.get_note(pos)? {
+            Some(note) => note,
+            None => return Ok(())
+        };
+        let key = (pos as u64).try_to_vec().unwrap();
+        let mut tx = DBTransaction::new();
+        tx.delete(COL_NOTE, &key);
+        tx.delete(COL_NULLIFIER, &key);
+        tx.delete(COL_PENDING, &self.note_nullifier(note).try_to_vec().unwrap());
+        Ok(self.db.write(tx)?)
+    }
+
+    // removes the notes spent on chain, nullifiers are taken from the contract nullifier list,
+    // returns the number of removed notes
+    pub fn scan_nullifiers(&self, nullifiers: &[Num<P::Fr>]) -> Result<usize, StateError> {
+        let spent = nullifiers.iter()
+            .map(|n| n.try_to_vec().unwrap())
+            .collect::<HashSet<_>>();
+        let mut removed = 0;
+        for (k, v) in self.db.iter(COL_NOTE) {
+            let pos = decode::<u64>(&k)? as usize;
+            let nullifier = self.note_nullifier(self.decode_note(&v)?);
+            if spent.contains(&nullifier.try_to_vec().unwrap()) {
+                self.remove_note(pos)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn is_pending(&self, nullifier: Num<P::Fr>) -> Result<bool, StateError> {
+        Ok(self.db.get(COL_PENDING, &nullifier.try_to_vec().unwrap())?.is_some())
+    }
+
+    // excludes the notes with these nullifiers from new transactions until rollback_pending
+    pub fn mark_spent(&self, nullifiers: &[Num<P::Fr>]) -> Result<(), StateError> {
+        let mut tx = DBTransaction::new();
+        for n in nullifiers {
+            tx.put(COL_PENDING, &n.try_to_vec().unwrap(), &[1u8]);
+        }
+        Ok(self.db.write(tx)?)
+    }
+
+    pub fn rollback_pending(&self) -> Result<(), StateError> {
+        let mut tx = DBTransaction::new();
+        for (k, _) in self.db.iter(COL_PENDING) {
+            tx.delete(COL_PENDING, &k);
+        }
+        Ok(self.db.write(tx)?)
     }
 
-    fn gen_num_leaves(&self) -> usize {
-        self.db.get(COL_DEFAULT, KEY_NUM_LEAVES).unwrap()
-            .map(|v| u64::try_from_slice(&v).unwrap() as usize).unwrap()
+    fn gen_num_leaves(&self) -> Result<usize, StateError> {
+        match self.db.get(COL_DEFAULT, KEY_NUM_LEAVES)? {
+            Some(v) => decode::<u64>(&v).map(|v| v as usize),
+            None => Err(StateError::Corrupted(io::Error::new(io::ErrorKind::NotFound, "num_leaves is not set")))
+        }
     }
 
     fn set_num_leaves(&self, tx: &mut DBTransaction, v: usize) {
         tx.put(COL_DEFAULT, KEY_NUM_LEAVES, &(v as u64).try_to_vec().unwrap());
     }
 
-    fn update_merkle_path(&self, tx: &mut DBTransaction, mut pos: usize, value: Num<P::Fr>) {
+    fn update_merkle_path(&self, tx: &mut DBTransaction, mut pos: usize, value: Num<P::Fr>) -> Result<(), StateError> {
         let mut root = value;
         self.set_cell(tx, (0, pos), value);
 
-        for i in 0..H::USIZE {
+        for i in 0..P::H::USIZE {
             root = if pos & 1 == 1 {
-                poseidon(&[self.get_cell((i, pos-1)), root], self.params.compress())
+                poseidon(&[self.get_cell((i, pos-1))?, root], self.params.compress())
             } else {
-                poseidon(&[root, self.get_cell((i, pos+1))], self.params.compress())
+                poseidon(&[root, self.get_cell((i, pos+1))?], self.params.compress())
             };
             pos >>= 1;
             self.set_cell(tx, (i + 1, pos), root);
         }
+        Ok(())
+    }
+
+    // fresh diversified address (d, pk_d) of this wallet
+    pub fn generate_address<R: Rng>(&self, rng: &mut R) -> (Num<P::Fr>, Num<P::Fr>) {
+        let d = num!(rand_biguint(rng, self.params.note_chunks()[0] * 8));
+        let pk_d = derive_key_pk_d(d, self.dk, self.params).x;
+        (d, pk_d)
+    }
+
+    pub fn merkle_root(&self) -> Result<Num<P::Fr>, StateError> {
+        self.get_cell((P::H::USIZE, 0))
+    }
+
+    // number of leaves added so far, the next leaf goes at this position
+    pub fn num_leaves(&self) -> Result<usize, StateError> {
+        self.gen_num_leaves()
     }
 
-    fn get_merkle_proof(&self, pos: usize) -> MerkleProof<P::Fr, P::H> {
+    pub fn merkle_proof(&self, pos: usize) -> Result<MerkleProof<P::Fr, P::H>, StateError> {
         let sibling = (0..P::H::USIZE)
             .map(|i| self.get_cell((i, (pos >> i) ^ 1)))
-            .collect();
+            .collect::<Result<_, _>>()?;
         let path = (0..P::H::USIZE).map(|i| (pos >> i) & 1 == 1).collect();
-        MerkleProof { sibling, path }
+        Ok(MerkleProof { sibling, path })
+    }
+
+    pub fn verify_merkle_proof(&self, note_hash: Num<P::Fr>, proof: &MerkleProof<P::Fr, P::H>, root: Num<P::Fr>) -> bool {
+        poseidon_merkle_proof_root(note_hash, proof, self.params.compress()) == root
     }
 
-    pub fn add_leaf(&self, note_hash: Num<P::Fr>, note: Option<Note<P::Fr>>) {
-        let num_leaves = self.gen_num_leaves();
+    pub fn add_leaf(&self, note_hash: Num<P::Fr>, note: Option<Note<P::Fr>>) -> Result<(), StateError> {
+        let num_leaves = self.gen_num_leaves()?;
         let mut tx = DBTransaction::new();
-        self.update_merkle_path(&mut tx, num_leaves, note_hash);
+        self.update_merkle_path(&mut tx, num_leaves, note_hash)?;
         if let Some(note) = note {
             self.set_note(&mut tx, num_leaves, note);
         }
         self.set_num_leaves(&mut tx,num_leaves + 1);
-        self.db.write(tx).unwrap()
-    }
 
-    pub fn get_note_list(&self) -> Vec<(usize,Note<P::Fr>)> {
-        self.db.iter(COL_NOTE)
-            .map(|(k, v)| 
-                (u64::try_from_slice(&k).unwrap() as usize, <Note<P::Fr>>::try_from_slice(&v).unwrap())
-            ).collect()
+        // cells on the path of the new leaf are rewritten
+        let mut cache = self.cell_cache.borrow_mut();
+        for i in 0..=P::H::USIZE {
+            cache.remove((i, num_leaves >> i));
+        }
+        Ok(self.db.write(tx)?)
     }
 
-    pub fn total_balance(&self) -> Num<P::Fr> {
-        self.get_note_list().into_iter()
-            .fold(num!(0), |acc, item| acc + item.1.v)
+    // digest signed by the wallet for the transaction and checked by c_tx_verify in the circuit,
+    // for external signers
+    pub fn transaction_sighash(&self, tx: &Tx<P>) -> Num<P::Fr> {
+        let in_note_hash = tx.input.iter().map(|&e| note_hash(e, self.params)).collect::<Vec<_>>();
+        let out_note_hash = tx.output.iter().map(|&e| note_hash(e, self.params)).collect::<Vec<_>>();
+        tx_hash(&in_note_hash, &out_note_hash, self.params)
     }
 
-    pub fn make_transaction_object<R: Rng>(
-        &self,
-        rng: &mut R,
-        recv_addr: (Num<P::Fr>, Num<P::Fr>),
-        amount: BigUint,
-        delta: BigInt,
-    ) -> Option<(TransferPub<P>, TransferSec<P>, Vec<u8>)> {
-        assert!(P::OUT::USIZE >= 2);
-
-        let mut note = self.get_note_list().into_iter()
-            .map(|e| (e.0, e.1, Into::<BigUint>::into(e.1.v)))
-            .collect::<Vec<_>>();
-        note.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap());
-    
-        let mut spending_amount: BigUint =
-            note.iter().take(P::IN::USIZE).map(|e| e.2.clone()).sum();
-
-        spending_amount = (spending_amount.to_bigint().unwrap() + &delta).to_biguint()?;
+    // add_leaf for consecutive leaves, the cells above the new leaves are hashed once per level
+    // instead of once per leaf, and are written in a single db transaction
+    pub fn add_leaves(&self, items: &[(Num<P::Fr>, Option<Note<P::Fr>>)]) -> Result<(), StateError> {
+        if items.is_empty() {
+            return Ok(());
+        }
+        let num_leaves = self.gen_num_leaves()?;
+        let mut tx = DBTransaction::new();
+        let mut cells = HashMap::new();
+        for (i, &(note_hash, note)) in items.iter().enumerate() {
+            cells.insert((0, num_leaves + i), note_hash);
+            if let Some(note) = note {
+                self.set_note(&mut tx, num_leaves + i, note);
+            }
+        }
 
-        if spending_amount < amount {
-            None
+        if num_leaves == 0 {
+            // a fresh tree, e.g. a rescan of the full history, is built level by level without db reads
+            let leaves = items.iter().map(|e| e.0).collect::<Vec<_>>();
+            for (i, level) in rebuild_tree(&leaves, &self.default_cell_value, self.params.compress()).into_iter().enumerate() {
+                for (pos, v) in level.into_iter().enumerate() {
+                    cells.insert((i, pos), v);
+                }
+            }
         } else {
-            let mut indexes: Vec<usize> = (0..std::cmp::min(P::IN::USIZE, note.len())).collect();
-            let note_len = note.len();
-            if note.len() > P::IN::USIZE {
-                for i in 0..P::IN::USIZE {
-                    let i1 = P::IN::USIZE - i - 1;
-                    let i2 = note_len - i - 1;
-
-                    let delta = &note[i1].2 - &note[i2].2;
-                    if &spending_amount - &delta < amount {
-                        break;
+            // [from, to] is the range of the updated cells at level i
+            let (mut from, mut to) = (num_leaves, num_leaves + items.len() - 1);
+            for i in 0..P::H::USIZE {
+                let cell = |pos: usize| -> Result<Num<P::Fr>, StateError> {
+                    match cells.get(&(i, pos)) {
+                        Some(&v) => Ok(v),
+                        None => self.get_cell((i, pos))
                     }
-                    spending_amount -= delta;
-                    indexes[i1] = i2;
+                };
+                let level = ((from >> 1)..=(to >> 1))
+                    .map(|pos| Ok((pos, poseidon(&[cell(2 * pos)?, cell(2 * pos + 1)?], self.params.compress()))))
+                    .collect::<Result<Vec<_>, StateError>>()?;
+                for (pos, v) in level {
+                    cells.insert((i + 1, pos), v);
                 }
+                from >>= 1;
+                to >>= 1;
             }
+        }
 
-            let sender_note = {
-                let d = num!(rand_biguint(rng, NOTE_CHUNKS[0]));
-                let pk_d = derive_key_pk_d(d, self.dk, self.params).x;
-                let v = num!(&spending_amount - &amount);
-                let st = num!(rand_biguint(rng, NOTE_CHUNKS[3]));
+        for (&pos, &v) in cells.iter() {
+            self.set_cell(&mut tx, pos, v);
+        }
+        self.set_num_leaves(&mut tx, num_leaves + items.len());
 
-                Note { d, pk_d, v, st }
-            };
+        let mut cache = self.cell_cache.borrow_mut();
+        for &pos in cells.keys() {
+            cache.remove(pos);
+        }
+        Ok(self.db.write(tx)?)
+    }
 
+    // decrypts a single encrypted note, None if it is not addressed to this wallet
+    pub fn try_decrypt(&self, memo: &[u8]) -> Option<Note<P::Fr>> {
+        note_decrypt_in(self.dk, memo, self.params)
+            .filter(|note| derive_key_pk_d(note.d, self.dk, self.params).x == note.pk_d)
+    }
 
-            let receiver_note = {
-                let (d, pk_d) = recv_addr;
-                let v = num!(amount);
-                let st = num!(rand_biguint(rng, NOTE_CHUNKS[3]));
+    // notes of this wallet among the encrypted notes, in the order of memos
+    pub fn scan(&self, memos: &[&[u8]]) -> Vec<Note<P::Fr>> {
+        scan_notes(self.dk, memos, self.params)
+            .into_iter()
+            .map(|(_, note)| note)
+            .filter(|note| derive_key_pk_d(note.d, self.dk, self.params).x == note.pk_d)
+            .collect()
+    }
 
-                Note { d, pk_d, v, st }
-            };
+    // Each message holds P::OUT encrypted notes, the notes of the k-th message are placed
+    // at leaves first_leaf_index + k*P::OUT ... Notes owned by this wallet are stored,
+    // the merkle tree itself is filled from the contract utxo list with add_leaf.
+    // A note is stored only if its leaf is already in the tree and matches its hash:
+    // the contract does not bind messages to out_hash, so unchecked notes may be forged.
+    // Returns the number of newly found notes.
+    pub fn scan_messages(&self, messages: &[Vec<u8>], first_leaf_index: usize) -> Result<usize, StateError> {
+        let enc_size = encrypted_note_size(self.params);
+        let num_leaves = self.gen_num_leaves()?;
+        let mut found = 0;
+        let mut tx = DBTransaction::new();
 
-            let tx = {
-                let input = indexes
-                    .iter()
-                    .map(|&i| note[i].1.clone())
-                    .chain((indexes.len()..P::IN::USIZE).map(|_| Note {
-                        d: num!(rand_biguint(rng, NOTE_CHUNKS[0])),
-                        pk_d: rng.gen(),
-                        v: num!(0),
-                        st: num!(rand_biguint(rng, NOTE_CHUNKS[3])),
-                    }))
-                    .collect();
-
-                let output = [sender_note, receiver_note]
-                    .iter()
-                    .cloned()
-                    .chain((2..P::OUT::USIZE).map(|_| Note {
-                        d: num!(rand_biguint(rng, NOTE_CHUNKS[0])),
-                        pk_d: rng.gen(),
-                        v: num!(0),
-                        st: num!(rand_biguint(rng, NOTE_CHUNKS[3])),
-                    }))
-                    .collect();
+        for (k, msg) in messages.iter().enumerate() {
+            let chunks = msg.chunks(enc_size).take(P::OUT::USIZE).collect::<Vec<_>>();
+            for (j, note) in scan_notes(self.dk, &chunks, self.params) {
+                // outgoing notes are decryptable by the sender too, keep only our own
+                if derive_key_pk_d(note.d, self.dk, self.params).x != note.pk_d {
+                    continue;
+                }
 
-                Tx { input, output }
-            };
+                let pos = first_leaf_index + k * P::OUT::USIZE + j;
+                if pos >= num_leaves || self.get_cell((0, pos))? != note_hash(note, self.params) {
+                    continue;
+                }
 
-            let assets = {
-                let mut res = vec![];
-                for n in tx.output.iter() {
-                    let esk = rng.gen();
-                    res.extend(note_encrypt(esk, self.dk, *n, self.params));
+                if self.get_note(pos)?.is_none() {
+                    self.set_note(&mut tx, pos, note);
+                    found += 1;
                 }
-                res
-            };
+            }
+        }
 
-            let memo = {
-                let mut h = Keccak256::new(); 
-                h.update(&assets);
-                let hash = h.finalize();
-                Num::from_binary_be(&hash)
-            };
+        self.db.write(tx)?;
+        Ok(found)
+    }
 
-            let in_proof = indexes
-                .iter()
-                .map(|&i| self.get_merkle_proof(note[i].0))
-                .chain((indexes.len()..P::IN::USIZE).map(|_| MerkleProof {
-                    sibling: SizedVec(vec![num!(0); P::H::USIZE], PhantomData),
-                    path: SizedVec(vec![false; P::H::USIZE], PhantomData),
-                }))
-                .collect();
-            let in_note_hash = tx
-                .input
-                .iter()
-                .map(|&e| note_hash(e, self.params))
-                .collect::<Vec<_>>();
-            let out_note_hash = tx
-                .output
-                .iter()
-                .map(|&e| note_hash(e, self.params))
-                .collect::<SizedVec<_, P::OUT>>();
+    // Appends the leaves of the transactions, out_hashes are taken from the contract utxo list
+    // (P::OUT per message), and picks up the notes of this wallet. Restores a wallet from sk
+    // when called on a fresh state with the full history. Returns the number of found notes.
+    pub fn rescan(&self, messages: &[Vec<u8>], out_hashes: &[Num<P::Fr>]) -> Result<usize, StateError> {
+        if out_hashes.len() != messages.len() * P::OUT::USIZE {
+            return Err(StateError::OutHashCount { messages: messages.len(), out_hashes: out_hashes.len() });
+        }
+        let first_leaf_index = self.gen_num_leaves()?;
+        self.add_leaves(&out_hashes.iter().map(|&h| (h, None)).collect::<Vec<_>>())?;
+        self.scan_messages(messages, first_leaf_index)
+    }
 
-            let (eddsa_s, eddsa_r, eddsa_a) = {
-                let m = tx_hash(&in_note_hash, &out_note_hash.0, self.params);
-                let (s, r) = self.wallet.sign(m, &self.params);
-                (s.into_other(), r, self.xsk)
-            };
+    fn decode_unspent_note(&self, k: &[u8], v: &[u8]) -> Result<Option<(usize, Note<P::Fr>)>, StateError> {
+        let pos = decode::<u64>(k)? as usize;
+        if self.is_nullified(pos)? {
+            return Ok(None);
+        }
+        Ok(Some((pos, self.decode_note(v)?)))
+    }
 
-            let transfer_sec = TransferSec {
-                tx,
-                in_proof,
-                eddsa_s,
-                eddsa_r,
-                eddsa_a,
-            };
+    // unspent notes read lazily from the db, nullified ones are skipped
+    pub fn iter_notes(&self) -> impl Iterator<Item=Result<(usize, Note<P::Fr>), StateError>> + '_ {
+        self.db.iter(COL_NOTE).filter_map(move |(k, v)| self.decode_unspent_note(&k, &v).transpose())
+    }
 
-            let transfer_pub = {
-                let root = self.get_cell((P::H::USIZE, 0));
-                let nullifier = in_note_hash
-                    .iter()
-                    .map(|&e| nullfifier(e, self.xsk, self.params))
-                    .collect();
-                let out_hash = out_note_hash;
-                let delta = prepare_delta(delta);
-
-                TransferPub {
-                    root,
-                    nullifier,
-                    out_hash,
-                    delta,
-                    memo,
-                }
-            };
+    // unspent notes, nullified ones are skipped
+    pub fn get_note_list(&self) -> Result<Vec<(usize,Note<P::Fr>)>, StateError> {
+        self.iter_notes().collect()
+    }
 
-            Some((transfer_pub, transfer_sec, assets))
-        }
+    pub fn balance(&self, asset: Num<P::Fr>) -> Result<Num<P::Fr>, StateError> {
+        self.iter_notes().try_fold(num!(0), |acc, item| {
+            let (_, note) = item?;
+            Ok(if note.asset == asset { acc + note.v } else { acc })
+        })
     }
-}
 
-pub fn gen_test_data() -> (
-    TransferPub<PoolBN256<IN, OUT, H>>,
-    TransferSec<PoolBN256<IN, OUT, H>>,
-) {
-    let db = kvdb_memorydb::create(NUM_COLS as u32);
-    let mut rng = thread_rng();
-    let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+    // balance of default_asset
+    pub fn total_balance(&self) -> Result<Num<P::Fr>, StateError> {
+        self.balance(default_asset())
+    }
 
-    let state = ClientState::new(&db, &wallet, &*POOL_PARAMS);
-    for i in 0..(1<<8) {
-        let mut note: Note<Fr> = rng.gen();
-        note.pk_d = derive_key_pk_d(note.d, state.dk, &*POOL_PARAMS).x;
-        let hash = note_hash(note, &*POOL_PARAMS);
-        state.add_leaf(hash, Some(note));
+    // total_balance as an integer, notes spent by pending transactions are still counted
+    pub fn confirmed_balance(&self) -> Result<BigUint, StateError> {
+        Ok(self.total_balance()?.into())
     }
 
+    // confirmed_balance without the notes spent by pending transactions, see mark_spent
+    pub fn spendable_balance(&self) -> Result<BigUint, StateError> {
+        let mut res = num!(0);
+        for item in self.iter_notes() {
+            let (_, note) = item?;
+            if note.asset == default_asset() && !self.is_pending(self.note_nullifier(note))? {
+                res = res + note.v;
+            }
+        }
+        Ok(res.into())
+    }
+
+    pub fn make_transaction_object<R: Rng>(
+        &self,
+        rng: &mut R,
+        recv_addr: (Num<P::Fr>, Num<P::Fr>),
+        amount: BigUint,
+        delta: BigInt,
+        fee: BigUint,
+    ) -> Result<(TransferPub<P>, TransferSec<P>, Vec<u8>), TxBuildError> {
+        self.make_transaction_object_with_strategy(rng, recv_addr, amount, delta, fee, SelectionStrategy::BranchAndBound)
+    }
+
+    pub fn make_transaction_object_with_strategy<R: Rng>(
+        &self,
+        rng: &mut R,
+        recv_addr: (Num<P::Fr>, Num<P::Fr>),
+        amount: BigUint,
+        delta: BigInt,
+        fee: BigUint,
+        strategy: SelectionStrategy,
+    ) -> Result<(TransferPub<P>, TransferSec<P>, Vec<u8>), TxBuildError> {
+        self.make_transaction(rng, default_asset(), &[(recv_addr, amount)], delta, fee, strategy)
+    }
+
+    // spends notes of the asset only, delta and fee are in this asset too
+    pub fn make_transaction_object_asset<R: Rng>(
+        &self,
+        rng: &mut R,
+        asset: Num<P::Fr>,
+        recv_addr: (Num<P::Fr>, Num<P::Fr>),
+        amount: BigUint,
+        delta: BigInt,
+        fee: BigUint,
+    ) -> Result<(TransferPub<P>, TransferSec<P>, Vec<u8>), TxBuildError> {
+        self.make_transaction(rng, asset, &[(recv_addr, amount)], delta, fee, SelectionStrategy::BranchAndBound)
+    }
+
+    // one output note per recipient and the change note, other outputs are zero notes
+    pub fn make_transaction_object_multi<R: Rng>(
+        &self,
+        rng: &mut R,
+        recipients: &[((Num<P::Fr>, Num<P::Fr>), BigUint)],
+        delta: BigInt,
+        fee: BigUint,
+    ) -> Result<(TransferPub<P>, TransferSec<P>, Vec<u8>), TxBuildError> {
+        self.make_transaction(rng, default_asset(), recipients, delta, fee, SelectionStrategy::BranchAndBound)
+    }
+
+    fn make_transaction<R: Rng>(
+        &self,
+        rng: &mut R,
+        asset: Num<P::Fr>,
+        recipients: &[((Num<P::Fr>, Num<P::Fr>), BigUint)],
+        delta: BigInt,
+        fee: BigUint,
+        strategy: SelectionStrategy,
+    ) -> Result<(TransferPub<P>, TransferSec<P>, Vec<u8>), TxBuildError> {
+        assert!(recipients.len() + 1 <= P::OUT::USIZE);
+
+        let limit_amount = BigInt::one() << (self.params.note_chunks()[2] * 8 - 1);
+        if delta >= limit_amount || delta < -limit_amount {
+            return Err(TxBuildError::DeltaOutOfRange);
+        }
+
+        let mut note = vec![];
+        for item in self.iter_notes() {
+            let (pos, n) = item?;
+            if n.asset == asset && !self.is_pending(self.note_nullifier(n))? {
+                note.push((pos, n, Into::<BigUint>::into(n.v)));
+            }
+        }
+        note.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap());
+    
+        let available: BigUint =
+            note.iter().take(P::IN::USIZE).map(|e| e.2.clone()).sum();
+
+        // relayer fee is paid out of the spent notes
+        let required = recipients.iter().map(|e| e.1.clone()).sum::<BigUint>() + &fee;
+
+        if (available.to_bigint().unwrap() + &delta).to_biguint().map_or(true, |v| v < required) {
+            return Err(if note.is_empty() {
+                TxBuildError::NoNotes
+            } else {
+                let required = (required.to_bigint().unwrap() - &delta).to_biguint().unwrap_or_default();
+                TxBuildError::InsufficientFunds { available, required }
+            });
+        }
+
+        let values = note.iter().map(|e| e.2.to_bigint().unwrap()).collect::<Vec<_>>();
+        let need = required.to_bigint().unwrap() - &delta;
+        let indexes = select_notes(&values, &need, P::IN::USIZE, strategy);
+
+        let spent: BigInt = indexes.iter().map(|&i| values[i].clone()).sum();
+        let change = (spent - need).to_biguint().unwrap();
+
+        let inputs = indexes.iter().map(|&i| (note[i].0, note[i].1)).collect::<Vec<_>>();
+        self.build_transaction(rng, asset, recipients, delta, fee, change, &inputs)
+    }
+
+    // spends exactly the notes at input_positions instead of the greedy selection
+    pub fn make_transaction_object_with_inputs<R: Rng>(
+        &self,
+        rng: &mut R,
+        recv_addr: (Num<P::Fr>, Num<P::Fr>),
+        amount: BigUint,
+        delta: BigInt,
+        fee: BigUint,
+        input_positions: &[usize],
+    ) -> Result<(TransferPub<P>, TransferSec<P>, Vec<u8>), TxBuildError> {
+        assert!(P::OUT::USIZE >= 2);
+
+        let limit_amount = BigInt::one() << (self.params.note_chunks()[2] * 8 - 1);
+        if delta >= limit_amount || delta < -limit_amount {
+            return Err(TxBuildError::DeltaOutOfRange);
+        }
+
+        if input_positions.len() > P::IN::USIZE {
+            return Err(TxBuildError::TooManyInputs);
+        }
+
+        let mut inputs = Vec::with_capacity(input_positions.len());
+        for (i, &pos) in input_positions.iter().enumerate() {
+            if input_positions[..i].contains(&pos) {
+                return Err(TxBuildError::DuplicateNote(pos));
+            }
+            match self.get_note(pos)? {
+                Some(note) if !self.is_nullified(pos)?
+                    && !self.is_pending(self.note_nullifier(note))? => inputs.push((pos, note)),
+                _ => return Err(TxBuildError::UnknownNote(pos))
+            }
+        }
+
+        let asset = inputs.first().map_or(default_asset(), |e| e.1.asset);
+        if inputs.iter().any(|e| e.1.asset != asset) {
+            return Err(TxBuildError::MixedAssets);
+        }
+
+        let available: BigUint = inputs.iter().map(|e| Into::<BigUint>::into(e.1.v)).sum();
+        let required = &amount + &fee;
+
+        let spending_amount = match (available.to_bigint().unwrap() + &delta).to_biguint() {
+            Some(v) if v >= required => v,
+            _ => {
+                let required = (required.to_bigint().unwrap() - &delta).to_biguint().unwrap_or_default();
+                return Err(TxBuildError::InsufficientFunds { available, required });
+            }
+        };
+
+        self.build_transaction(rng, asset, &[(recv_addr, amount)], delta, fee, spending_amount - required, &inputs)
+    }
+
+    // inputs are (position, note) of the spent notes, change goes to a fresh address of this wallet,
+    // all the notes are of the same asset
+    fn build_transaction<R: Rng>(
+        &self,
+        rng: &mut R,
+        asset: Num<P::Fr>,
+        recipients: &[((Num<P::Fr>, Num<P::Fr>), BigUint)],
+        delta: BigInt,
+        fee: BigUint,
+        change: BigUint,
+        inputs: &[(usize, Note<P::Fr>)],
+    ) -> Result<(TransferPub<P>, TransferSec<P>, Vec<u8>), TxBuildError> {
+        if !self.wallet.can_sign() {
+            return Err(TxBuildError::ViewOnly);
+        }
+
+        let sender_note = {
+            let d = num!(rand_biguint(rng, self.params.note_chunks()[0]));
+            let pk_d = derive_key_pk_d(d, self.dk, self.params).x;
+            let v = num!(change);
+            let st = num!(rand_biguint(rng, self.params.note_chunks()[3]));
+
+            Note { d, pk_d, v, st, asset }
+        };
+
+
+        let receiver_notes = recipients.iter().map(|((d, pk_d), amount)| {
+            let v = num!(amount.clone());
+            let st = num!(rand_biguint(rng, self.params.note_chunks()[3]));
+
+            Note { d: *d, pk_d: *pk_d, v, st, asset }
+        }).collect::<Vec<_>>();
+
+        let tx = {
+            let input = inputs
+                .iter()
+                .map(|e| e.1)
+                .chain((inputs.len()..P::IN::USIZE).map(|_| Note {
+                    d: num!(rand_biguint(rng, self.params.note_chunks()[0])),
+                    pk_d: rng.gen(),
+                    v: num!(0),
+                    st: num!(rand_biguint(rng, self.params.note_chunks()[3])),
+                    asset,
+                }))
+                .collect();
+
+            // zero notes are encrypted too, so pk_d must be a valid point
+            let output = std::iter::once(sender_note)
+                .chain(receiver_notes.into_iter())
+                .chain((recipients.len() + 1..P::OUT::USIZE).map(|_| {
+                    let d = num!(rand_biguint(rng, self.params.note_chunks()[0]));
+                    Note {
+                        d,
+                        pk_d: derive_key_pk_d(d, rng.gen(), self.params).x,
+                        v: num!(0),
+                        st: num!(rand_biguint(rng, self.params.note_chunks()[3])),
+                        asset,
+                    }
+                }))
+                .collect();
+
+            Tx { input, output }
+        };
+
+        let assets = {
+            let mut res = vec![];
+            for n in tx.output.iter() {
+                let esk = rng.gen();
+                res.extend(note_encrypt(esk, self.dk, *n, self.params)?);
+            }
+            res
+        };
+
+        let memo = {
+            let mut h = Keccak256::new(); 
+            h.update(&assets);
+            let hash = h.finalize();
+            Num::from_binary_be(&hash)
+        };
+
+        let in_proof = inputs
+            .iter()
+            .map(|e| self.merkle_proof(e.0))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .chain((inputs.len()..P::IN::USIZE).map(|_| MerkleProof {
+                sibling: SizedVec(vec![num!(0); P::H::USIZE], PhantomData),
+                path: SizedVec(vec![false; P::H::USIZE], PhantomData),
+            }))
+            .collect();
+        let in_note_hash = tx
+            .input
+            .iter()
+            .map(|&e| note_hash(e, self.params))
+            .collect::<Vec<_>>();
+        let out_note_hash = tx
+            .output
+            .iter()
+            .map(|&e| note_hash(e, self.params))
+            .collect::<SizedVec<_, P::OUT>>();
+
+        let (eddsa_s, eddsa_r, eddsa_a) = {
+            let m = tx_hash(&in_note_hash, &out_note_hash.0, self.params);
+            let (s, r) = self.wallet.sign(m, &self.params);
+            (s.into_other(), r, self.xsk)
+        };
+
+        let transfer_sec = TransferSec {
+            tx,
+            in_proof,
+            eddsa_s,
+            eddsa_r,
+            eddsa_a,
+        };
+
+        let transfer_pub = {
+            let root = self.merkle_root()?;
+            let nullifier = in_note_hash
+                .iter()
+                .map(|&e| nullfifier(e, self.xsk, self.params))
+                .collect();
+            let out_hash = out_note_hash;
+            let (deposit_amount, withdraw_amount) = match delta.to_biguint() {
+                Some(v) => (num!(v), num!(0)),
+                None => (num!(0), num!((-&delta).to_biguint().unwrap())),
+            };
+            let delta = prepare_delta(delta, self.params);
+            let fee = num!(fee);
+
+            TransferPub {
+                root,
+                nullifier,
+                out_hash,
+                delta,
+                deposit_amount,
+                withdraw_amount,
+                fee,
+                asset,
+                memo,
+            }
+        };
+
+        // the chosen notes go first in tx.input, keep them from being spent again
+        self.mark_spent(&transfer_pub.nullifier.0[..inputs.len()])?;
+
+        Ok((transfer_pub, transfer_sec, assets))
+    }
+}
+
+impl<'p, 'db, 'w, P: PoolParams, DB:KeyValueDB> ClientState<'p, 'db, 'w, P, DB, ViewingKey<P>> {
+    // scans notes and tracks the balance, transaction building returns TxBuildError::ViewOnly
+    pub fn new_view_only(db: &'db DB, viewing_key: &'w ViewingKey<P>, params: &'p P) -> Result<Self, StateError> {
+        let mut state = Self::new(db, viewing_key, params)?;
+        state.dk = viewing_key.dk;
+        Ok(state)
+    }
+}
+
+pub fn gen_test_data() -> (
+    TransferPub<PoolBN256<IN, OUT, H>>,
+    TransferSec<PoolBN256<IN, OUT, H>>,
+) {
+    let db = kvdb_memorydb::create(NUM_COLS as u32);
+    let mut rng = thread_rng();
+    let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+
+    let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+    let items = (0..(1<<8)).map(|_| {
+        let mut note: Note<Fr> = rng.gen();
+        note.pk_d = derive_key_pk_d(note.d, state.dk, &*POOL_PARAMS).x;
+        (note_hash(note, &*POOL_PARAMS), Some(note))
+    }).collect::<Vec<_>>();
+    state.add_leaves(&items).unwrap();
+
     let recv_addr = {
         let d = num!(rand_biguint(&mut rng, NOTE_CHUNKS[0]));
         let pk_d = POOL_PARAMS.jubjub().edwards_g().mul(rng.gen(), POOL_PARAMS.jubjub()).x;
         (d, pk_d)
     };
 
-    let (p,s, _) = state.make_transaction_object(&mut rng, recv_addr, BigUint::from(1u64), BigInt::zero()).unwrap();
+    let (p,s, _) = state.make_transaction_object(&mut rng, recv_addr, BigUint::from(1u64), BigInt::zero(), BigUint::zero()).unwrap();
     (p, s)
 
 }
@@ -388,8 +1288,971 @@ pub fn gen_test_data() -> (
 #[cfg(test)]
 mod data_test {
     use super::*;
+    use crate::native::tx::{parse_delta, tx_verify};
+    use fawkes_crypto::native::ecc::EdwardsPoint;
+    use crate::{POOL_PARAMS4, TPoolParams4};
+    use fawkes_crypto::native::bn256::JubJubBN256;
+    use fawkes_crypto::native::poseidon::PoseidonParams;
+    use typenum::U3;
+
+    // a state over db with one note of each of values sent to the wallet itself, at leaves 0..values.len()
+    fn state_with_notes<'db, 'w>(db: &'db InMemory, wallet: &'w NativeWallet<TPoolParams>, values: &[u64]) -> ClientState<'static, 'db, 'w, TPoolParams, InMemory, NativeWallet<TPoolParams>> {
+        let mut rng = thread_rng();
+        let state = ClientState::new(db, wallet, &*POOL_PARAMS).unwrap();
+        for &v in values.iter() {
+            let mut note: Note<Fr> = rng.gen();
+            note.pk_d = derive_key_pk_d(note.d, state.dk, &*POOL_PARAMS).x;
+            note.v = num!(BigUint::from(v));
+            state.add_leaf(note_hash(note, &*POOL_PARAMS), Some(note)).unwrap();
+        }
+        state
+    }
+
+
+
+    #[test]
+    fn test_note_nullifier_columns() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+
+        let note: Note<Fr> = rng.gen();
+        let nullifier: Num<Fr> = rng.gen();
+        let key = 5u64.try_to_vec().unwrap();
+
+        let mut tx = DBTransaction::new();
+        state.set_note(&mut tx, 5, note);
+        tx.put(COL_NULLIFIER, &key, &nullifier.try_to_vec().unwrap());
+        db.write(tx).unwrap();
+
+        assert!(state.get_note(5).unwrap() == Some(note));
+        let stored = db.get(COL_NULLIFIER, &key).unwrap().unwrap();
+        assert!(<Num<Fr>>::try_from_slice(&stored).unwrap() == nullifier);
+    }
+
+    #[test]
+    fn test_invalid_recipient() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+
+        let mut note: Note<Fr> = rng.gen();
+        note.pk_d = derive_key_pk_d(note.d, state.dk, &*POOL_PARAMS).x;
+        note.v = num!(5);
+        state.add_leaf(note_hash(note, &*POOL_PARAMS), Some(note)).unwrap();
+
+        let pk_d = loop {
+            let pk_d: Num<Fr> = rng.gen();
+            if EdwardsPoint::subgroup_decompress(pk_d, POOL_PARAMS.jubjub()).is_none() {
+                break pk_d;
+            }
+        };
+        let bad_note = Note { pk_d, ..note };
+        assert!(note_encrypt(rng.gen(), state.dk, bad_note, &*POOL_PARAMS) == Err(EncryptError::InvalidRecipient));
+        let zero_note = Note { pk_d: num!(0), ..note };
+        assert!(note_encrypt(rng.gen(), state.dk, zero_note, &*POOL_PARAMS) == Err(EncryptError::InvalidRecipient));
+
+        let res = state.make_transaction_object(&mut rng, (note.d, pk_d), BigUint::from(3u64), BigInt::zero(), BigUint::zero());
+        assert!(res.err() == Some(TxBuildError::InvalidRecipient));
+        // the inputs are not left pending
+        assert!(state.spendable_balance().unwrap() == BigUint::from(5u64));
+    }
+
+    #[test]
+    fn test_pending_notes() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = state_with_notes(&db, &wallet, &[5, 5]);
+        let recv_addr = state.generate_address(&mut rng);
+
+        let (p, _, _) = state.make_transaction_object(&mut rng, recv_addr, BigUint::from(3u64), BigInt::zero(), BigUint::zero()).unwrap();
+        state.mark_spent(&p.nullifier.0).unwrap();
+
+        let res = state.make_transaction_object(&mut rng, recv_addr, BigUint::from(3u64), BigInt::zero(), BigUint::zero());
+        assert!(res.err() == Some(TxBuildError::NoNotes));
+
+        state.rollback_pending().unwrap();
+        assert!(state.make_transaction_object(&mut rng, recv_addr, BigUint::from(3u64), BigInt::zero(), BigUint::zero()).is_ok());
+    }
+
+    #[test]
+    fn test_confirmed_balance() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+
+        for &v in [5u64, 7].iter() {
+            let mut note: Note<Fr> = rng.gen();
+            note.pk_d = derive_key_pk_d(note.d, state.dk, &*POOL_PARAMS).x;
+            note.v = num!(BigUint::from(v));
+            state.add_leaf(note_hash(note, &*POOL_PARAMS), Some(note)).unwrap();
+        }
+
+        let balance = state.confirmed_balance().unwrap().to_string();
+        assert_eq!(balance.parse::<BigUint>().unwrap(), BigUint::from(12u64));
+        assert_eq!(state.spendable_balance().unwrap(), BigUint::from(12u64));
+
+        let recv_addr = state.generate_address(&mut rng);
+        let (_, s, _) = state.make_transaction_object(&mut rng, recv_addr, BigUint::from(3u64), BigInt::zero(), BigUint::zero()).unwrap();
+        let spent: BigUint = s.tx.input.iter().fold(num!(0), |acc, n| acc + n.v).into();
+        assert_eq!(state.confirmed_balance().unwrap(), BigUint::from(12u64));
+        assert_eq!(state.spendable_balance().unwrap(), BigUint::from(12u64) - spent);
+
+        state.rollback_pending().unwrap();
+        assert_eq!(state.spendable_balance().unwrap(), BigUint::from(12u64));
+    }
+
+    #[test]
+    fn test_iter_notes() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+        assert!(state.iter_notes().next().is_none());
+
+        for &v in [5u64, 7, 9].iter() {
+            let mut note: Note<Fr> = rng.gen();
+            note.pk_d = derive_key_pk_d(note.d, state.dk, &*POOL_PARAMS).x;
+            note.asset = default_asset();
+            note.v = num!(BigUint::from(v));
+            state.add_leaf(note_hash(note, &*POOL_PARAMS), Some(note)).unwrap();
+        }
+        state.remove_note(1).unwrap();
+
+        let notes = state.iter_notes().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(notes.iter().map(|e| e.0).collect::<Vec<_>>(), vec![0, 2]);
+        assert!(notes == state.get_note_list().unwrap());
+        assert!(state.total_balance().unwrap() == num!(14));
+    }
+
+    #[test]
+    fn test_note_nullifier() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+
+        let mut note: Note<Fr> = rng.gen();
+        note.pk_d = derive_key_pk_d(note.d, state.dk, &*POOL_PARAMS).x;
+        note.v = num!(5);
+        state.add_leaf(note_hash(note, &*POOL_PARAMS), Some(note)).unwrap();
+
+        let recv_addr = state.generate_address(&mut rng);
+        let (p, _, _) = state.make_transaction_object(&mut rng, recv_addr, BigUint::from(3u64), BigInt::zero(), BigUint::zero()).unwrap();
+        assert!(p.nullifier.0[0] == state.note_nullifier(note));
+    }
+
+    #[test]
+    fn test_reset() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+
+        for _ in 0..3 {
+            let mut note: Note<Fr> = rng.gen();
+            note.pk_d = derive_key_pk_d(note.d, state.dk, &*POOL_PARAMS).x;
+            note.v = num!(5);
+            state.add_leaf(note_hash(note, &*POOL_PARAMS), Some(note)).unwrap();
+        }
+        let recv_addr = state.generate_address(&mut rng);
+        state.make_transaction_object(&mut rng, recv_addr, BigUint::from(3u64), BigInt::zero(), BigUint::zero()).unwrap();
+        state.nullify_note(0).unwrap();
+
+        state.reset().unwrap();
+        assert!(state.total_balance().unwrap() == num!(0));
+        assert_eq!(state.num_leaves().unwrap(), 0);
+        assert!(state.merkle_root().unwrap() == empty_tree_root(&*POOL_PARAMS));
+        for col in 1..NUM_COLS as u32 {
+            assert_eq!(db.iter(col).count(), 0);
+        }
+
+        // the state stays usable
+        state.add_leaf(rng.gen(), None).unwrap();
+        assert_eq!(state.num_leaves().unwrap(), 1);
+        assert!(ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap().merkle_root().unwrap() == state.merkle_root().unwrap());
+    }
+
+    #[test]
+    fn test_remove_note() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+
+        let mut notes = vec![];
+        for &v in [5u64, 7, 11].iter() {
+            let mut note: Note<Fr> = rng.gen();
+            note.pk_d = derive_key_pk_d(note.d, state.dk, &*POOL_PARAMS).x;
+            note.v = num!(BigUint::from(v));
+            state.add_leaf(note_hash(note, &*POOL_PARAMS), Some(note)).unwrap();
+            notes.push(note);
+        }
+        let root = state.merkle_root().unwrap();
+        assert!(state.total_balance().unwrap() == num!(23));
+
+        state.remove_note(0).unwrap();
+        assert!(state.total_balance().unwrap() == num!(18));
+        assert!(state.get_note(0).unwrap().is_none());
+        assert!(state.merkle_root().unwrap() == root);
+
+        let nullifiers = vec![rng.gen(), state.note_nullifier(notes[2]), state.note_nullifier(notes[0])];
+        assert_eq!(state.scan_nullifiers(&nullifiers).unwrap(), 1);
+        assert!(state.total_balance().unwrap() == num!(7));
+        assert_eq!(state.scan_nullifiers(&nullifiers).unwrap(), 0);
+
+        let proof = state.merkle_proof(2).unwrap();
+        assert!(state.verify_merkle_proof(note_hash(notes[2], &*POOL_PARAMS), &proof, root));
+    }
+
+    #[test]
+    fn test_nullify_note() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+        let recv_addr = state.generate_address(&mut rng);
+
+        let mut note: Note<Fr> = rng.gen();
+        note.pk_d = derive_key_pk_d(note.d, state.dk, &*POOL_PARAMS).x;
+        note.v = num!(5);
+        state.add_leaf(note_hash(note, &*POOL_PARAMS), Some(note)).unwrap();
+        assert!(state.total_balance().unwrap() == num!(5));
+
+        assert!(state.make_transaction_object(&mut rng, recv_addr, BigUint::from(3u64), BigInt::zero(), BigUint::zero()).is_ok());
+        let res = state.make_transaction_object(&mut rng, recv_addr, BigUint::from(3u64), BigInt::zero(), BigUint::zero());
+        assert!(res.err() == Some(TxBuildError::NoNotes));
+
+        // the transaction is confirmed
+        state.nullify_note(0).unwrap();
+        state.rollback_pending().unwrap();
+        assert!(state.total_balance().unwrap() == num!(0));
+        assert!(state.get_note_list().unwrap().is_empty());
+        let res = state.make_transaction_object(&mut rng, recv_addr, BigUint::from(3u64), BigInt::zero(), BigUint::zero());
+        assert!(res.err() == Some(TxBuildError::NoNotes));
+    }
+
+    #[test]
+    fn test_explicit_inputs() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+        let recv_addr = state.generate_address(&mut rng);
+
+        let mut notes = vec![];
+        for _ in 0..3 {
+            let mut note: Note<Fr> = rng.gen();
+            note.pk_d = derive_key_pk_d(note.d, state.dk, &*POOL_PARAMS).x;
+            note.v = num!(5);
+            state.add_leaf(note_hash(note, &*POOL_PARAMS), Some(note)).unwrap();
+            notes.push(note);
+        }
 
+        let res = state.make_transaction_object_with_inputs(&mut rng, recv_addr, BigUint::from(6u64), BigInt::zero(), BigUint::zero(), &[0]);
+        assert!(res.err() == Some(TxBuildError::InsufficientFunds { available: BigUint::from(5u64), required: BigUint::from(6u64) }));
+        let res = state.make_transaction_object_with_inputs(&mut rng, recv_addr, BigUint::from(1u64), BigInt::zero(), BigUint::zero(), &[0, 0]);
+        assert!(res.err() == Some(TxBuildError::DuplicateNote(0)));
+        let res = state.make_transaction_object_with_inputs(&mut rng, recv_addr, BigUint::from(1u64), BigInt::zero(), BigUint::zero(), &[3]);
+        assert!(res.err() == Some(TxBuildError::UnknownNote(3)));
 
+        let (_, s, _) = state.make_transaction_object_with_inputs(&mut rng, recv_addr, BigUint::from(6u64), BigInt::zero(), BigUint::zero(), &[2, 0]).unwrap();
+        assert!(s.tx.input.0[0] == notes[2] && s.tx.input.0[1] == notes[0]);
+        assert!(s.tx.output.0[0].v == num!(4));
+
+        let res = state.make_transaction_object_with_inputs(&mut rng, recv_addr, BigUint::from(1u64), BigInt::zero(), BigUint::zero(), &[0]);
+        assert!(res.err() == Some(TxBuildError::UnknownNote(0)));
+    }
+
+    #[test]
+    fn test_selection_strategy() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+        let recv_addr = state.generate_address(&mut rng);
+
+        for &v in [1u64, 1, 2, 2, 3, 5, 8, 13].iter() {
+            let mut note: Note<Fr> = rng.gen();
+            note.pk_d = derive_key_pk_d(note.d, state.dk, &*POOL_PARAMS).x;
+            note.v = num!(BigUint::from(v));
+            state.add_leaf(note_hash(note, &*POOL_PARAMS), Some(note)).unwrap();
+        }
+
+        let strategies = [
+            (SelectionStrategy::MinimizeChange, 6),
+            (SelectionStrategy::ConsolidateSmallest, 6),
+            (SelectionStrategy::ExactLargest, 2),
+            (SelectionStrategy::BranchAndBound, 2),
+        ];
+        for &(strategy, num_inputs) in strategies.iter() {
+            let (p, s, _) = state.make_transaction_object_with_strategy(&mut rng, recv_addr, BigUint::from(15u64), BigInt::from(-2), BigUint::from(1u64), strategy).unwrap();
+
+            let spent = s.tx.input.iter().fold(num!(0), |acc, n| acc + n.v);
+            let created = s.tx.output.iter().fold(num!(0), |acc, n| acc + n.v);
+            assert!(spent + parse_delta(p.delta, &*POOL_PARAMS) == created + p.fee);
+            assert!(p.deposit_amount == num!(0) && p.withdraw_amount == num!(2));
+            assert_eq!(s.tx.input.iter().filter(|n| n.v != num!(0)).count(), num_inputs);
+
+            state.rollback_pending().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_branch_and_bound() {
+        let values = [13, 8, 5, 3, 2, 2, 1, 1].iter().map(|&v| BigInt::from(v)).collect::<Vec<_>>();
+        let spent = |indexes: &[usize]| -> BigInt { indexes.iter().map(|&i| values[i].clone()).sum() };
+
+        for &need in [18, 7, 4, 30].iter() {
+            let need = BigInt::from(need);
+            let bnb = select_notes(&values, &need, 6, SelectionStrategy::BranchAndBound);
+            assert!(spent(&bnb) == need, "exact match exists for {}", need);
+            for &strategy in [SelectionStrategy::MinimizeChange, SelectionStrategy::ExactLargest].iter() {
+                let greedy = select_notes(&values, &need, 6, strategy);
+                assert!(spent(&greedy) >= spent(&bnb));
+                assert!(greedy.len() >= bnb.len() || spent(&greedy) > need);
+            }
+        }
+
+        // no exact match, the least change
+        let values = [10, 10, 7].iter().map(|&v| BigInt::from(v)).collect::<Vec<_>>();
+        let bnb = select_notes(&values, &BigInt::from(8), 6, SelectionStrategy::BranchAndBound);
+        assert_eq!(bnb, vec![0]);
+        // nothing covers the amount, largest first fallback
+        let bnb = select_notes(&values, &BigInt::from(15), 1, SelectionStrategy::BranchAndBound);
+        assert_eq!(bnb, vec![0]);
+    }
+
+    #[test]
+    fn test_multi_recipient() {
+        type Params3 = PoolBN256<IN, U3, H>;
+        let params = Params3 {
+            jubjub: JubJubBN256::new(),
+            hash: PoseidonParams::<Fr>::new(2, 8, 53),
+            compress: PoseidonParams::<Fr>::new(3, 8, 53),
+            note: PoseidonParams::<Fr>::new(6, 8, 54),
+            tx: PoseidonParams::<Fr>::new(IN::USIZE + 3 + 1, 8, 54),
+            eddsa: PoseidonParams::<Fr>::new(4, 8, 53),
+            note_chunks: NOTE_CHUNKS,
+            phantom: PhantomData
+        };
+
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<Params3> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &params).unwrap();
+
+        let mut note: Note<Fr> = rng.gen();
+        note.pk_d = derive_key_pk_d(note.d, state.dk, &params).x;
+        note.v = num!(10);
+        state.add_leaf(note_hash(note, &params), Some(note)).unwrap();
+
+        let recipients = (0..2u64)
+            .map(|i| (state.generate_address(&mut rng), BigUint::from(i + 2)))
+            .collect::<Vec<_>>();
+        let (p, s, message) = state.make_transaction_object_multi(&mut rng, &recipients, BigInt::zero(), BigUint::from(1u64)).unwrap();
+
+        assert!(s.tx.output.0[0].v == num!(4));
+        assert!(s.tx.output.0[1].v == num!(2) && s.tx.output.0[2].v == num!(3));
+        let spent = s.tx.input.iter().fold(num!(0), |acc, n| acc + n.v);
+        let created = s.tx.output.iter().fold(num!(0), |acc, n| acc + n.v);
+        assert!(spent == created + p.fee);
+        assert_eq!(message.len(), 3 * encrypted_note_size(&params));
+    }
+
+    #[test]
+    fn test_multi_recipient_out4() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams4> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS4).unwrap();
+
+        for _ in 0..2 {
+            let mut note: Note<Fr> = rng.gen();
+            note.pk_d = derive_key_pk_d(note.d, state.dk, &*POOL_PARAMS4).x;
+            note.v = num!(10);
+            state.add_leaf(note_hash(note, &*POOL_PARAMS4), Some(note)).unwrap();
+        }
+
+        let recv_addr = (0..3).map(|_| {
+            let d = num!(rand_biguint(&mut rng, NOTE_CHUNKS[0]));
+            (d, derive_key_pk_d(d, rng.gen(), &*POOL_PARAMS4).x)
+        }).collect::<Vec<_>>();
+        let recipients = recv_addr.iter().map(|&a| (a, BigUint::from(5u64))).collect::<Vec<_>>();
+        let (p, s, _) = state.make_transaction_object_multi(&mut rng, &recipients, BigInt::from(-1), BigUint::zero()).unwrap();
+
+        assert!(s.tx.output.0[0].v == num!(4));
+        for i in 0..3 {
+            assert!(s.tx.output.0[i + 1].pk_d == recv_addr[i].1);
+        }
+
+        let ref mut cs = TestCS::<Fr>::new();
+        let ref p = CTransferPub::alloc(cs, Some(&p));
+        let ref s = CTransferSec::alloc(cs, Some(&s));
+        c_transfer(&p, &s, &*POOL_PARAMS4);
+    }
+
+    #[test]
+    fn test_wallet_from_seed() {
+        let w1 = NativeWallet::<TPoolParams>::from_seed(b"correct horse battery staple");
+        let w2 = NativeWallet::<TPoolParams>::from_seed(b"correct horse battery staple");
+        let w3 = NativeWallet::<TPoolParams>::from_seed(b"correct horse battery staples");
+        assert!(w1.sk == w2.sk);
+        assert!(w1.sk != w3.sk);
+        assert!(w1.xsk(&*POOL_PARAMS) == w2.xsk(&*POOL_PARAMS));
+    }
+
+    #[test]
+    fn test_wallet_from_mnemonic() {
+        let phrase = "legal winner thank year wave sausage worth useful legal winner thank yellow";
+        let w1 = NativeWallet::<TPoolParams>::from_mnemonic(phrase).unwrap();
+        let w2 = NativeWallet::<TPoolParams>::from_mnemonic(&format!("  {} ", phrase.to_uppercase().replace(" ", "\n"))).unwrap();
+        assert!(w1.sk == w2.sk);
+        assert!(w1.sk != NativeWallet::<TPoolParams>::from_seed(phrase.as_bytes()).sk);
+
+        // BIP39 seed of "abandon ... about" with an empty passphrase
+        let seed = "5eb00bbddcf069084889a8ab9155568165f5c453ccb85e70811aaed6f6da5fc1\
+                    9a5ac40b389cd370d086206dec8aa6c43daea6690f20ad3d8d48b2d2ce9e38e4";
+        let seed = (0..seed.len()).step_by(2).map(|i| u8::from_str_radix(&seed[i..i + 2], 16).unwrap()).collect::<Vec<_>>();
+        let w = NativeWallet::<TPoolParams>::from_mnemonic(&format!("{}about", "abandon ".repeat(11))).unwrap();
+        assert!(w.sk == NativeWallet::<TPoolParams>::from_seed(&seed).sk);
+
+        let vk1 = w1.viewing_key(&*POOL_PARAMS);
+        let vk2 = w2.viewing_key(&*POOL_PARAMS);
+        assert!(vk1.xsk == vk2.xsk && vk1.dk == vk2.dk);
+
+        assert!(matches!(NativeWallet::<TPoolParams>::from_mnemonic("abandon ability able"), Err(MnemonicError::InvalidPhrase(_))));
+        // wrong checksum and a word out of the wordlist
+        assert!(NativeWallet::<TPoolParams>::from_mnemonic(&"abandon ".repeat(12)).is_err());
+        assert!(NativeWallet::<TPoolParams>::from_mnemonic(&phrase.replace("wave", "wavez")).is_err());
+    }
+
+    #[test]
+    fn test_to_mnemonic() {
+        // BIP39 test vectors
+        assert_eq!(to_mnemonic(&[0u8; 16]).unwrap(), format!("{}about", "abandon ".repeat(11)));
+        assert_eq!(to_mnemonic(&[0x7fu8; 16]).unwrap(), "legal winner thank year wave sausage worth useful legal winner thank yellow");
+        assert!(matches!(to_mnemonic(&[0u8; 15]), Err(MnemonicError::InvalidEntropy(_))));
+
+        let mut rng = thread_rng();
+        let phrase = generate_mnemonic(&mut rng);
+        assert_eq!(phrase.split(' ').count(), 12);
+        assert!(NativeWallet::<TPoolParams>::from_mnemonic(&phrase).is_ok());
+    }
+
+    #[test]
+    fn test_insufficient_funds() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+        let recv_addr = state.generate_address(&mut rng);
+
+        let res = state.make_transaction_object(&mut rng, recv_addr, BigUint::from(1u64), BigInt::zero(), BigUint::zero());
+        assert!(res.err() == Some(TxBuildError::NoNotes));
+
+        let mut note: Note<Fr> = rng.gen();
+        note.pk_d = derive_key_pk_d(note.d, state.dk, &*POOL_PARAMS).x;
+        note.v = num!(5);
+        state.add_leaf(note_hash(note, &*POOL_PARAMS), Some(note)).unwrap();
+
+        let res = state.make_transaction_object(&mut rng, recv_addr, BigUint::from(4u64), BigInt::from(-1), BigUint::from(1u64));
+        assert!(res.err() == Some(TxBuildError::InsufficientFunds { available: BigUint::from(5u64), required: BigUint::from(6u64) }));
+
+        let res = state.make_transaction_object(&mut rng, recv_addr, BigUint::from(1u64), BigInt::one() << 63, BigUint::zero());
+        assert!(res.err() == Some(TxBuildError::DeltaOutOfRange));
+    }
+
+    #[test]
+    fn test_multi_asset() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+        let recv_addr = state.generate_address(&mut rng);
+        let other_asset = num!(7);
+
+        for &(asset, v) in [(default_asset(), num!(5)), (other_asset, num!(4)), (other_asset, num!(3))].iter() {
+            let mut note: Note<Fr> = rng.gen();
+            note.pk_d = derive_key_pk_d(note.d, state.dk, &*POOL_PARAMS).x;
+            note.v = v;
+            note.asset = asset;
+            state.add_leaf(note_hash(note, &*POOL_PARAMS), Some(note)).unwrap();
+        }
+        assert!(state.total_balance().unwrap() == num!(5));
+        assert!(state.balance(other_asset).unwrap() == num!(7));
+
+        let res = state.make_transaction_object(&mut rng, recv_addr, BigUint::from(6u64), BigInt::zero(), BigUint::zero());
+        assert!(res.err() == Some(TxBuildError::InsufficientFunds { available: BigUint::from(5u64), required: BigUint::from(6u64) }));
+
+        let (p, s, _) = state.make_transaction_object_asset(&mut rng, other_asset, recv_addr, BigUint::from(6u64), BigInt::zero(), BigUint::zero()).unwrap();
+        assert!(p.asset == other_asset);
+        assert!(s.tx.input.iter().chain(s.tx.output.iter()).all(|n| n.asset == other_asset));
+        assert_eq!(s.tx.input.iter().filter(|n| n.v != num!(0)).count(), 2);
+        assert!(s.tx.output[0].v == num!(1));
+
+        state.rollback_pending().unwrap();
+        let res = state.make_transaction_object_with_inputs(&mut rng, recv_addr, BigUint::from(1u64), BigInt::zero(), BigUint::zero(), &[0, 1]);
+        assert!(res.err() == Some(TxBuildError::MixedAssets));
+
+        let ref mut cs = TestCS::<Fr>::new();
+        let ref p = CTransferPub::alloc(cs, Some(&p));
+        let ref s = CTransferSec::alloc(cs, Some(&s));
+        c_transfer(p, s, &*POOL_PARAMS);
+    }
+
+    #[test]
+    fn test_view_only() {
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let viewing_key = wallet.viewing_key(&*POOL_PARAMS);
+
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+        let view_db = kvdb_memorydb::create(NUM_COLS as u32);
+        let view_state = ClientState::new_view_only(&view_db, &viewing_key, &*POOL_PARAMS).unwrap();
+        assert!(view_state.dk == state.dk);
+
+        let (d, pk_d) = state.generate_address(&mut rng);
+        let note = Note { d, pk_d, v: num!(5), st: num!(0), asset: default_asset() };
+        let mut foreign: Note<Fr> = rng.gen();
+        foreign.pk_d = derive_key_pk_d(foreign.d, rng.gen(), &*POOL_PARAMS).x;
+
+        let mut message = vec![];
+        let mut out_hashes = vec![];
+        for &n in std::iter::once(&note).chain((1..OUT::USIZE).map(|_| &foreign)) {
+            message.extend(note_encrypt(rng.gen(), rng.gen(), n, &*POOL_PARAMS).unwrap());
+            out_hashes.push(note_hash(n, &*POOL_PARAMS));
+        }
+
+        assert_eq!(view_state.rescan(&[message], &out_hashes).unwrap(), 1);
+        assert!(view_state.total_balance().unwrap() == num!(5));
+        assert!(view_state.note_nullifier(note) == state.note_nullifier(note));
+
+        let res = view_state.make_transaction_object(&mut rng, (d, pk_d), BigUint::from(3u64), BigInt::zero(), BigUint::zero());
+        assert!(res.err() == Some(TxBuildError::ViewOnly));
+        assert!(view_state.get_note_list().unwrap().len() == 1);
+    }
+
+    #[test]
+    fn test_generate_address() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+
+        let (d, pk_d) = state.generate_address(&mut rng);
+        let note = Note {
+            d,
+            pk_d,
+            v: num!(7),
+            st: num!(rand_biguint(&mut rng, NOTE_CHUNKS[3] * 8)),
+            asset: default_asset(),
+        };
+
+        let msg = note_encrypt(rng.gen(), rng.gen(), note, &*POOL_PARAMS).unwrap();
+        assert!(note_decrypt_in(state.dk, &msg, &*POOL_PARAMS) == Some(note));
+    }
+
+    #[test]
+    fn test_try_decrypt() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+
+        let (d, pk_d) = state.generate_address(&mut rng);
+        let note = Note {
+            d,
+            pk_d,
+            v: num!(7),
+            st: num!(rand_biguint(&mut rng, NOTE_CHUNKS[3] * 8)),
+            asset: default_asset(),
+        };
+        let own = note_encrypt(rng.gen(), rng.gen(), note, &*POOL_PARAMS).unwrap();
+
+        let mut foreign_note: Note<Fr> = rng.gen();
+        foreign_note.pk_d = derive_key_pk_d(foreign_note.d, rng.gen(), &*POOL_PARAMS).x;
+        let foreign = note_encrypt(rng.gen(), rng.gen(), foreign_note, &*POOL_PARAMS).unwrap();
+
+        assert!(state.try_decrypt(&own) == Some(note));
+        assert!(state.try_decrypt(&foreign).is_none());
+        assert!(state.try_decrypt(&own[1..]).is_none());
+
+        let notes = state.scan(&[&foreign[..], &own[..], &own[..]]);
+        assert!(notes == vec![note, note]);
+    }
+
+    #[test]
+    fn test_rescan() {
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let old_db = kvdb_memorydb::create(NUM_COLS as u32);
+        let old_state = ClientState::new(&old_db, &wallet, &*POOL_PARAMS).unwrap();
+
+        let mut messages = vec![];
+        let mut out_hashes = vec![];
+        for k in 0..3 {
+            let mut own: Note<Fr> = rng.gen();
+            let (d, pk_d) = old_state.generate_address(&mut rng);
+            own.d = d;
+            own.pk_d = pk_d;
+            own.v = num!(BigUint::from(k as u64 + 1));
+            let mut foreign: Note<Fr> = rng.gen();
+            foreign.pk_d = derive_key_pk_d(foreign.d, rng.gen(), &*POOL_PARAMS).x;
+
+            let mut message = vec![];
+            for &n in [foreign, own].iter().chain((2..OUT::USIZE).map(|_| &foreign)) {
+                message.extend(note_encrypt(rng.gen(), rng.gen(), n, &*POOL_PARAMS).unwrap());
+                out_hashes.push(note_hash(n, &*POOL_PARAMS));
+            }
+            messages.push(message);
+        }
+
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+        let res = state.rescan(&messages, &out_hashes[1..]);
+        assert!(matches!(res, Err(StateError::OutHashCount { messages: 3, .. })));
+        assert_eq!(state.gen_num_leaves().unwrap(), 0);
+
+        assert_eq!(state.rescan(&messages, &out_hashes).unwrap(), 3);
+        assert!(state.total_balance().unwrap() == num!(6));
+        assert_eq!(state.gen_num_leaves().unwrap(), out_hashes.len());
+    }
+
+    #[test]
+    fn test_export_state() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+        for i in 0..(1<<8) {
+            if i % 2 == 0 {
+                let mut note: Note<Fr> = rng.gen();
+                note.pk_d = derive_key_pk_d(note.d, state.dk, &*POOL_PARAMS).x;
+                state.add_leaf(note_hash(note, &*POOL_PARAMS), Some(note)).unwrap();
+            } else {
+                state.add_leaf(rng.gen(), None).unwrap();
+            }
+        }
+        state.nullify_note(0).unwrap();
+
+        let data = state.export_state().unwrap();
+        let new_db = kvdb_memorydb::create(NUM_COLS as u32);
+        let new_state = ClientState::import_state(&new_db, &wallet, &*POOL_PARAMS, &data).unwrap();
+
+        assert!(new_state.merkle_root().unwrap() == state.merkle_root().unwrap());
+        assert!(new_state.total_balance().unwrap() == state.total_balance().unwrap());
+        assert_eq!(new_state.gen_num_leaves().unwrap(), 1<<8);
+        assert!(new_state.export_state().unwrap() == data);
+    }
+
+    #[test]
+    fn test_empty_tree_root() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+
+        let root = empty_tree_root(&*POOL_PARAMS);
+        assert!(root == state.default_cell_value[H::USIZE]);
+        assert!(root == state.merkle_root().unwrap());
+    }
+
+    // EMPTY_ROOT of the contract is generated from empty_tree_root by pool-contract/build.rs for its TREE_HEIGHT = 32
+    #[cfg(not(feature = "small-tree"))]
+    #[test]
+    fn test_contract_empty_root() {
+        assert_eq!(H::USIZE, 32);
+        let mut root = num!(0);
+        for _ in 0..32 {
+            root = poseidon(&[root, root], POOL_PARAMS.compress());
+        }
+        assert!(root == empty_tree_root(&*POOL_PARAMS));
+    }
+
+    #[test]
+    fn test_root_and_num_leaves() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+        assert_eq!(state.num_leaves().unwrap(), 0);
+
+        let mut roots = vec![state.merkle_root().unwrap()];
+        for i in 0..3 {
+            state.add_leaf(rng.gen(), None).unwrap();
+            assert_eq!(state.num_leaves().unwrap(), i + 1);
+            let root = state.merkle_root().unwrap();
+            assert!(!roots.contains(&root));
+            roots.push(root);
+        }
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+
+        let leaves: Vec<Num<Fr>> = (0..5).map(|_| rng.gen()).collect();
+        for &leaf in leaves.iter() {
+            state.add_leaf(leaf, None).unwrap();
+        }
+
+        let root = state.merkle_root().unwrap();
+        for (i, &leaf) in leaves.iter().enumerate() {
+            let proof = state.merkle_proof(i).unwrap();
+            assert!(poseidon_merkle_proof_root(leaf, &proof, POOL_PARAMS.compress()) == root);
+        }
+    }
+
+    #[test]
+    fn test_transaction_sighash() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+
+        let mut note: Note<Fr> = rng.gen();
+        note.pk_d = derive_key_pk_d(note.d, state.dk, &*POOL_PARAMS).x;
+        note.v = num!(10);
+        state.add_leaf(note_hash(note, &*POOL_PARAMS), Some(note)).unwrap();
+
+        let recv_addr = state.generate_address(&mut rng);
+        let (_, s, _) = state.make_transaction_object(&mut rng, recv_addr, BigUint::from(3u64), BigInt::zero(), BigUint::zero()).unwrap();
+        let sighash = state.transaction_sighash(&s.tx);
+        assert!(tx_verify(s.eddsa_s.into_other(), s.eddsa_r, s.eddsa_a, sighash, &*POOL_PARAMS));
+        assert!(!tx_verify(s.eddsa_s.into_other(), s.eddsa_r, s.eddsa_a, sighash + num!(1), &*POOL_PARAMS));
+    }
+
+    #[cfg(feature = "rocksdb")]
+    #[test]
+    fn test_open_rocksdb() {
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let path = std::env::temp_dir().join(format!("pool-wallet-{}", rng.gen::<u64>()));
+        let path = path.to_str().unwrap();
+
+        let root = {
+            let persistent = PersistentClientState::open_rocksdb(path, &wallet, &*POOL_PARAMS).unwrap();
+            let state = persistent.state().unwrap();
+            for &v in [5u64, 7].iter() {
+                let mut note: Note<Fr> = rng.gen();
+                note.pk_d = derive_key_pk_d(note.d, state.dk, &*POOL_PARAMS).x;
+                note.v = num!(BigUint::from(v));
+                state.add_leaf(note_hash(note, &*POOL_PARAMS), Some(note)).unwrap();
+            }
+            state.merkle_root().unwrap()
+        };
+
+        let persistent = PersistentClientState::open_rocksdb(path, &wallet, &*POOL_PARAMS).unwrap();
+        let state = persistent.state().unwrap();
+        assert_eq!(state.num_leaves().unwrap(), 2);
+        assert!(state.merkle_root().unwrap() == root);
+        assert_eq!(state.confirmed_balance().unwrap(), BigUint::from(12u64));
+
+        drop(state);
+        drop(persistent);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_rebuild_tree() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+
+        let leaves: Vec<Num<Fr>> = (0..77).map(|_| rng.gen()).collect();
+        for &leaf in leaves.iter() {
+            state.add_leaf(leaf, None).unwrap();
+        }
+        let default_cells = default_cell_values(&*POOL_PARAMS);
+        let mut expected = vec![leaves.clone()];
+        for i in 0..H::USIZE {
+            let level = hash_level_seq(&expected[i], default_cells[i], POOL_PARAMS.compress());
+            expected.push(level);
+        }
+
+        let tree = rebuild_tree(&leaves, &default_cells, POOL_PARAMS.compress());
+        assert_eq!(tree.len(), H::USIZE + 1);
+        for i in 0..=H::USIZE {
+            assert!(tree[i] == expected[i], "level {}", i);
+        }
+        assert!(tree[H::USIZE] == vec![state.merkle_root().unwrap()]);
+        assert!(rebuild_tree(&[], &default_cells, POOL_PARAMS.compress())[H::USIZE].is_empty());
+    }
+
+    #[test]
+    fn test_add_leaves() {
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+        let bulk_db = kvdb_memorydb::create(NUM_COLS as u32);
+        let bulk_state = ClientState::new(&bulk_db, &wallet, &*POOL_PARAMS).unwrap();
+
+        let mut items = vec![];
+        for i in 0..103 {
+            if i % 10 == 0 {
+                let mut note: Note<Fr> = rng.gen();
+                note.pk_d = derive_key_pk_d(note.d, state.dk, &*POOL_PARAMS).x;
+                items.push((note_hash(note, &*POOL_PARAMS), Some(note)));
+            } else {
+                items.push((rng.gen(), None));
+            }
+        }
+        for &(h, note) in items.iter() {
+            state.add_leaf(h, note).unwrap();
+        }
+        // leaves are appended to a nonempty tree, at an odd position
+        for &(h, note) in items[..3].iter() {
+            bulk_state.add_leaf(h, note).unwrap();
+        }
+        bulk_state.add_leaves(&items[3..]).unwrap();
+        bulk_state.add_leaves(&[]).unwrap();
+
+        assert_eq!(bulk_state.num_leaves().unwrap(), items.len());
+        let root = state.merkle_root().unwrap();
+        assert!(bulk_state.merkle_root().unwrap() == root);
+        for &pos in [0, 3, 50, 102].iter() {
+            let proof = bulk_state.merkle_proof(pos).unwrap();
+            assert!(poseidon_merkle_proof_root(items[pos].0, &proof, POOL_PARAMS.compress()) == root);
+        }
+        assert!(bulk_state.get_note_list().unwrap() == state.get_note_list().unwrap());
+
+        // a fresh state is built by rebuild_tree
+        let fresh_db = kvdb_memorydb::create(NUM_COLS as u32);
+        let fresh_state = ClientState::new(&fresh_db, &wallet, &*POOL_PARAMS).unwrap();
+        fresh_state.add_leaves(&items).unwrap();
+        assert!(fresh_state.merkle_root().unwrap() == root);
+        assert!(fresh_state.get_note_list().unwrap() == state.get_note_list().unwrap());
+        fresh_state.add_leaf(rng.gen(), None).unwrap();
+        state.add_leaf(fresh_state.get_cell((0, items.len())).unwrap(), None).unwrap();
+        assert!(fresh_state.merkle_root().unwrap() == state.merkle_root().unwrap());
+    }
+
+    #[test]
+    fn test_cell_cache() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+
+        let leaves: Vec<Num<Fr>> = (0..255).map(|_| rng.gen()).collect();
+        for &leaf in leaves.iter() {
+            state.add_leaf(leaf, None).unwrap();
+        }
+
+        // merkle proofs of IN inputs, as make_transaction_object reads them
+        let positions = [0, 17, 63, 128, 200, 254];
+        let root = state.merkle_root().unwrap();
+        let misses = state.cell_cache.borrow().misses;
+        for _ in 0..2 {
+            for &pos in positions.iter() {
+                let proof = state.merkle_proof(pos).unwrap();
+                assert!(poseidon_merkle_proof_root(leaves[pos], &proof, POOL_PARAMS.compress()) == root);
+            }
+        }
+        let reads = state.cell_cache.borrow().misses - misses;
+        let lookups = 2 * positions.len() * H::USIZE;
+        println!("db reads for {} proofs of a 256-leaf tree: {} of {} cell lookups", 2 * positions.len(), reads, lookups);
+        assert!(reads <= positions.len() * H::USIZE);
+
+        // cached cells on the path of the last leaf are not stale, it fills the small-tree as well
+        let leaf = rng.gen();
+        state.add_leaf(leaf, None).unwrap();
+        let root = state.merkle_root().unwrap();
+        assert!(root == poseidon_merkle_proof_root(leaf, &state.merkle_proof(255).unwrap(), POOL_PARAMS.compress()));
+        for &pos in positions.iter() {
+            let proof = state.merkle_proof(pos).unwrap();
+            assert!(poseidon_merkle_proof_root(leaves[pos], &proof, POOL_PARAMS.compress()) == root);
+        }
+    }
+
+    #[test]
+    fn test_verify_merkle_proof() {
+        let db = kvdb_memorydb::create(NUM_COLS as u32);
+        let mut rng = thread_rng();
+        let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
+        state.add_leaf(rng.gen(), None).unwrap();
+
+        let leaf = rng.gen();
+        state.add_leaf(leaf, None).unwrap();
+        let proof = state.merkle_proof(1).unwrap();
+        assert!(state.verify_merkle_proof(leaf, &proof, state.merkle_root().unwrap()));
+        assert!(!state.verify_merkle_proof(rng.gen(), &proof, state.merkle_root().unwrap()));
+    }
+
+    #[test]
+    fn test_scan_messages() {
+        let mut rng = thread_rng();
+
+        let sender_db = kvdb_memorydb::create(NUM_COLS as u32);
+        let sender_wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let sender = ClientState::new(&sender_db, &sender_wallet, &*POOL_PARAMS).unwrap();
+
+        let receiver_db = kvdb_memorydb::create(NUM_COLS as u32);
+        let receiver_wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let receiver = ClientState::new(&receiver_db, &receiver_wallet, &*POOL_PARAMS).unwrap();
+
+        let mut note: Note<Fr> = rng.gen();
+        note.pk_d = derive_key_pk_d(note.d, sender.dk, &*POOL_PARAMS).x;
+        note.v = num!(10);
+        sender.add_leaf(note_hash(note, &*POOL_PARAMS), Some(note)).unwrap();
+
+        let recv_addr = receiver.generate_address(&mut rng);
+
+        let (p, _, message) = sender.make_transaction_object(&mut rng, recv_addr, BigUint::from(3u64), BigInt::zero(), BigUint::zero()).unwrap();
+        // message with index 0 is a foreign transaction
+        for _ in 0..OUT::USIZE {
+            receiver.add_leaf(rng.gen(), None).unwrap();
+        }
+        for h in p.out_hash.iter() {
+            receiver.add_leaf(*h, None).unwrap();
+        }
+
+        let messages = vec![vec![0u8; message.len()], message];
+        assert_eq!(receiver.scan_messages(&messages, 0).unwrap(), 1);
+        assert_eq!(receiver.scan_messages(&messages, 0).unwrap(), 0);
+
+        let notes = receiver.get_note_list().unwrap();
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].0, OUT::USIZE + 1);
+        assert!(notes[0].1.v == num!(3));
+    }
+
+    #[test]
+    fn test_scan_messages_future_leaf() {
+        let mut rng = thread_rng();
+
+        let sender_db = kvdb_memorydb::create(NUM_COLS as u32);
+        let sender_wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let sender = ClientState::new(&sender_db, &sender_wallet, &*POOL_PARAMS).unwrap();
+
+        let receiver_db = kvdb_memorydb::create(NUM_COLS as u32);
+        let receiver_wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
+        let receiver = ClientState::new(&receiver_db, &receiver_wallet, &*POOL_PARAMS).unwrap();
+
+        let mut note: Note<Fr> = rng.gen();
+        note.pk_d = derive_key_pk_d(note.d, sender.dk, &*POOL_PARAMS).x;
+        note.v = num!(10);
+        sender.add_leaf(note_hash(note, &*POOL_PARAMS), Some(note)).unwrap();
+
+        // a forged message for leaves the receiver has not added yet
+        let recv_addr = receiver.generate_address(&mut rng);
+        let (_, _, message) = sender.make_transaction_object(&mut rng, recv_addr, BigUint::from(3u64), BigInt::zero(), BigUint::zero()).unwrap();
+        assert_eq!(receiver.scan_messages(&[message], 0).unwrap(), 0);
+        assert!(receiver.get_note_list().unwrap().is_empty());
+        assert!(receiver.total_balance().unwrap() == num!(0));
+    }
 
     #[test]
     fn test_transfer() {
@@ -397,12 +2260,12 @@ mod data_test {
         let mut rng = thread_rng();
         let wallet = NativeWallet::<TPoolParams> {sk: rng.gen()};
 
-        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS);
+        let state = ClientState::new(&db, &wallet, &*POOL_PARAMS).unwrap();
         for i in 0..(1<<8) {
             let mut note: Note<Fr> = rng.gen();
             note.pk_d = derive_key_pk_d(note.d, state.dk, &*POOL_PARAMS).x;
             let hash = note_hash(note, &*POOL_PARAMS);
-            state.add_leaf(hash, Some(note));
+            state.add_leaf(hash, Some(note)).unwrap();
         }
 
         let recv_addr = {
@@ -411,7 +2274,7 @@ mod data_test {
             (d, pk_d)
         };
 
-        let (p, s, _) = state.make_transaction_object(&mut rng, recv_addr, BigUint::from(1u64), BigInt::zero()).unwrap();
+        let (p, s, _) = state.make_transaction_object(&mut rng, recv_addr, BigUint::from(1u64), BigInt::zero(), BigUint::zero()).unwrap();
 
         let ref mut cs = TestCS::<Fr>::new();
 
@@ -14,7 +14,7 @@ use num::bigint::{BigInt, BigUint};
 use crate::native::data::rand_biguint;
 
 use crate::constants::{
-    SEED_DECRYPTION_KEY, SEED_DIVERSIFIER, SEED_NOTE_HASH, SEED_NULLIFIER, SEED_TX_HASH,
+    SEED_DECRYPTION_KEY, SEED_DIVERSIFIER, SEED_NOTE_HASH, SEED_NOTE_MAC, SEED_NULLIFIER, SEED_TX_HASH,
 };
 use num::{One, Zero};
 use std::fmt::Debug;
@@ -42,6 +42,8 @@ pub trait PoolParams: Clone + Sized {
     fn note(&self) -> &PoseidonParams<Self::Fr>;
     fn tx(&self) -> &PoseidonParams<Self::Fr>;
     fn eddsa(&self) -> &PoseidonParams<Self::Fr>;
+    // byte widths of d, pk_d, v, st and asset in the encrypted notes and the circuit range checks
+    fn note_chunks(&self) -> &[usize; 5];
 }
 
 #[derive(Clone)]
@@ -52,6 +54,7 @@ pub struct PoolBN256<IN: Unsigned, OUT: Unsigned, H: Unsigned> {
     pub note: PoseidonParams<Fr>,
     pub tx: PoseidonParams<Fr>,
     pub eddsa: PoseidonParams<Fr>,
+    pub note_chunks: [usize; 5],
     pub phantom: PhantomData<(IN, OUT, H)>,
 }
 
@@ -86,9 +89,19 @@ impl<IN: Unsigned, OUT: Unsigned, H: Unsigned> PoolParams for PoolBN256<IN, OUT,
     fn eddsa(&self) -> &PoseidonParams<Self::Fr> {
         &self.eddsa
     }
+
+    fn note_chunks(&self) -> &[usize; 5] {
+        &self.note_chunks
+    }
 }
 
-pub const NOTE_CHUNKS: [usize; 4] = [10, 32, 8, 10];
+// default note layout, Note borsh and Rand impls use it
+pub const NOTE_CHUNKS: [usize; 5] = [10, 32, 8, 10, 4];
+
+// asset id of the pool's own token, the only one the contract accepts
+pub fn default_asset<F: Field>() -> Num<F> {
+    num!(0)
+}
 
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(bound(serialize = "", deserialize = ""))]
@@ -97,24 +110,20 @@ pub struct Note<F: Field> {
     pub pk_d: Num<F>,
     pub v: Num<F>,
     pub st: Num<F>,
+    pub asset: Num<F>,
 }
 
 
 
 impl<Fr:Field> Rand for Note<Fr> {
     fn rand<R: Rng>(rng: &mut R) -> Self {
-        Self {
-            d: num!(rand_biguint(rng, NOTE_CHUNKS[0] * 8)),
-            pk_d: num!(rand_biguint(rng, NOTE_CHUNKS[1] * 8)),
-            v: num!(rand_biguint(rng, NOTE_CHUNKS[2] * 8 / 2)),
-            st: num!(rand_biguint(rng, NOTE_CHUNKS[3] * 8)),
-        }
+        Self::rand_with_chunks(rng, &NOTE_CHUNKS)
     }
 }
 
 
 
-fn to_compressed(buf: &[u8], num_size: usize, chunks: &[usize]) -> Result<Vec<u8>, io::Error> {
+pub(crate) fn to_compressed(buf: &[u8], num_size: usize, chunks: &[usize]) -> Result<Vec<u8>, io::Error> {
     let buf_len = buf.len();
     let chunks_len = chunks.len();
     if buf_len % num_size != 0 || buf_len / num_size != chunks_len {
@@ -140,7 +149,7 @@ fn to_compressed(buf: &[u8], num_size: usize, chunks: &[usize]) -> Result<Vec<u8
     }
 }
 
-fn to_decompressed(buf: &[u8], num_size: usize, chunks: &[usize]) -> Result<Vec<u8>, io::Error> {
+pub(crate) fn to_decompressed(buf: &[u8], num_size: usize, chunks: &[usize]) -> Result<Vec<u8>, io::Error> {
     let buf_len = buf.len();
     let chunks_len = chunks.len();
     if buf_len != chunks.iter().sum::<usize>() {
@@ -159,28 +168,38 @@ fn to_decompressed(buf: &[u8], num_size: usize, chunks: &[usize]) -> Result<Vec<
     }
 }
 
-impl<T: Field> BorshSerialize for Note<T> {
-    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
+impl<T: Field> Note<T> {
+    // random note of default_asset
+    pub fn rand_with_chunks<R: Rng>(rng: &mut R, chunks: &[usize; 5]) -> Self {
+        Self {
+            d: num!(rand_biguint(rng, chunks[0] * 8)),
+            pk_d: num!(rand_biguint(rng, chunks[1] * 8)),
+            v: num!(rand_biguint(rng, chunks[2] * 8 / 2)),
+            st: num!(rand_biguint(rng, chunks[3] * 8)),
+            asset: default_asset(),
+        }
+    }
+
+    pub fn serialize_with_chunks<W: Write>(&self, writer: &mut W, chunks: &[usize; 5]) -> Result<(), io::Error> {
         let num_size = (T::NUM_BITS as usize - 1) / 8 + 1;
         let mut cur = Cursor::new(vec![]);
         self.d.serialize(&mut cur)?;
         self.pk_d.serialize(&mut cur)?;
         self.v.serialize(&mut cur)?;
         self.st.serialize(&mut cur)?;
+        self.asset.serialize(&mut cur)?;
         let buf = cur.into_inner();
-        writer.write(&to_compressed(&buf, num_size, &NOTE_CHUNKS)?)?;
+        writer.write(&to_compressed(&buf, num_size, chunks)?)?;
         Ok(())
     }
-}
 
-impl<T: Field> BorshDeserialize for Note<T> {
-    fn deserialize(buf: &mut &[u8]) -> Result<Self, io::Error> {
+    pub fn deserialize_with_chunks(buf: &mut &[u8], chunks: &[usize; 5]) -> Result<Self, io::Error> {
         let num_size = (T::NUM_BITS as usize - 1) / 8 + 1;
-        let note_size = NOTE_CHUNKS.iter().sum();
+        let note_size = chunks.iter().sum();
         if buf.len() < note_size {
             Err(io::Error::new(io::ErrorKind::InvalidData, "Too short data"))
         } else {
-            let data = to_decompressed(&buf[0..note_size], num_size, &NOTE_CHUNKS)?;
+            let data = to_decompressed(&buf[0..note_size], num_size, chunks)?;
             *buf = &buf[note_size..];
             let mut r = &data[..];
             Ok(Self {
@@ -188,9 +207,38 @@ impl<T: Field> BorshDeserialize for Note<T> {
                 pk_d: Num::deserialize(&mut r)?,
                 v: Num::deserialize(&mut r)?,
                 st: Num::deserialize(&mut r)?,
+                asset: Num::deserialize(&mut r)?,
             })
         }
     }
+
+    pub fn to_vec_with_chunks(&self, chunks: &[usize; 5]) -> Result<Vec<u8>, io::Error> {
+        let mut res = vec![];
+        self.serialize_with_chunks(&mut res, chunks)?;
+        Ok(res)
+    }
+
+    // the whole slice must be consumed, as in try_from_slice
+    pub fn from_slice_with_chunks(v: &[u8], chunks: &[usize; 5]) -> Result<Self, io::Error> {
+        let mut buf = v;
+        let res = Self::deserialize_with_chunks(&mut buf, chunks)?;
+        if !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not all bytes read"));
+        }
+        Ok(res)
+    }
+}
+
+impl<T: Field> BorshSerialize for Note<T> {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
+        self.serialize_with_chunks(writer, &NOTE_CHUNKS)
+    }
+}
+
+impl<T: Field> BorshDeserialize for Note<T> {
+    fn deserialize(buf: &mut &[u8]) -> Result<Self, io::Error> {
+        Self::deserialize_with_chunks(buf, &NOTE_CHUNKS)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -207,7 +255,13 @@ pub struct TransferPub<P: PoolParams> {
     pub nullifier: SizedVec<Num<P::Fr>, P::IN>,
     pub out_hash: SizedVec<Num<P::Fr>, P::OUT>,
     pub delta: Num<P::Fr>,
+    // exactly one of them equals |delta| for a nonzero delta, the other is zero
+    pub deposit_amount: Num<P::Fr>,
+    pub withdraw_amount: Num<P::Fr>,
+    pub fee: Num<P::Fr>,
     pub memo: Num<P::Fr>,
+    // asset of delta and fee
+    pub asset: Num<P::Fr>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -220,6 +274,51 @@ pub struct TransferSec<P: PoolParams> {
     pub eddsa_a: Num<P::Fr>,
 }
 
+impl<P: PoolParams> TransferPub<P> {
+    // borsh encoding of the transfer circuit public inputs in the order of CTransferPub,
+    // the same as input_vec_transfer of the contract
+    pub fn public_inputs(&self) -> Vec<u8> {
+        let mut res = vec![];
+        self.root.serialize(&mut res).unwrap();
+        for e in self.nullifier.iter().chain(self.out_hash.iter()) {
+            e.serialize(&mut res).unwrap();
+        }
+        for e in [self.delta, self.deposit_amount, self.withdraw_amount, self.fee, self.memo, self.asset].iter() {
+            e.serialize(&mut res).unwrap();
+        }
+        res
+    }
+
+    // borsh encoding of the contract's TransferAndUpdateRoot, fields are written in its order:
+    // root, nullifier, out_hash, delta, deposit_amount, fee, memo, asset, message, before_root,
+    // after_root, withdraw_account, withdraw_amount
+    pub fn to_contract_args(
+        &self,
+        message: &[u8],
+        before_root: Num<P::Fr>,
+        after_root: Num<P::Fr>,
+        withdraw_account: Option<String>,
+    ) -> Vec<u8> {
+        let mut res = vec![];
+        self.root.serialize(&mut res).unwrap();
+        // fixed size arrays on the contract side, no length prefix
+        for e in self.nullifier.iter().chain(self.out_hash.iter()) {
+            e.serialize(&mut res).unwrap();
+        }
+        self.delta.serialize(&mut res).unwrap();
+        self.deposit_amount.serialize(&mut res).unwrap();
+        self.fee.serialize(&mut res).unwrap();
+        self.memo.serialize(&mut res).unwrap();
+        self.asset.serialize(&mut res).unwrap();
+        message.to_vec().serialize(&mut res).unwrap();
+        before_root.serialize(&mut res).unwrap();
+        after_root.serialize(&mut res).unwrap();
+        withdraw_account.serialize(&mut res).unwrap();
+        self.withdraw_amount.serialize(&mut res).unwrap();
+        res
+    }
+}
+
 fn xor_crypt<D: Digest + Clone>(prefix: &D, data: &[u8]) -> Vec<u8> {
     let mut mask = vec![];
 
@@ -238,22 +337,41 @@ fn dh_prefix<F: Field>(dh_x: Num<F>, h: &[u8]) -> Keccak256 {
     res
 }
 
+// keyed by the DH secret, computed over the encrypted note
+fn note_mac<F: Field>(dh_x: Num<F>, h: &[u8], note_vec_enc: &[u8]) -> Vec<u8> {
+    let mut mac = dh_prefix(dh_x, h);
+    mac.update(SEED_NOTE_MAC);
+    mac.update(note_vec_enc);
+    mac.finalize().to_vec()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EncryptError {
+    // pk_d of the note is zero or not a compressed point of the prime order subgroup
+    InvalidRecipient,
+}
+
 pub fn note_encrypt<P: PoolParams>(
     esk: Num<P::Fs>,
     dk: Num<P::Fs>,
     note: Note<P::Fr>,
     params: &P,
-) -> Vec<u8> {
-    let pk_d = EdwardsPoint::subgroup_decompress(note.pk_d, params.jubjub()).unwrap();
+) -> Result<Vec<u8>, EncryptError> {
+    // x = 0 decompresses to the identity, the note would be encrypted with a known dh secret
+    if note.pk_d == num!(0) {
+        return Err(EncryptError::InvalidRecipient);
+    }
+    let pk_d = EdwardsPoint::subgroup_decompress(note.pk_d, params.jubjub()).ok_or(EncryptError::InvalidRecipient)?;
     let dh = pk_d.mul(esk, params.jubjub());
 
-    let note_vec = note.try_to_vec().unwrap();
+    let note_vec = note.to_vec_with_chunks(params.note_chunks()).unwrap();
 
     let mut hasher = Keccak256::new();
     hasher.update(&note_vec);
     let note_hash = hasher.finalize();
 
     let note_vec_enc = xor_crypt(&dh_prefix(dh.x, &note_hash), &note_vec);
+    let mac = note_mac(dh.x, &note_hash, &note_vec_enc);
 
     let epk = derive_key_pk_d(note.d, esk, params);
     let epk2 = dh.mul(dk.inverse(), params.jubjub());
@@ -264,7 +382,15 @@ pub fn note_encrypt<P: PoolParams>(
     res.extend(epk2.x.try_to_vec().unwrap());
     res.extend(note_hash);
     res.extend(note_vec_enc);
-    res
+    res.extend(mac);
+    Ok(res)
+}
+
+// size of a single encrypted note in the message: epk, epk2, note hash, the note itself and the mac
+pub fn encrypted_note_size<P: PoolParams>(params: &P) -> usize {
+    let note_size: usize = params.note_chunks().iter().sum();
+    let num_size = (P::Fr::NUM_BITS as usize - 1) / 8 + 1;
+    64 + 2 * num_size + note_size
 }
 
 fn note_decrypt<P: PoolParams>(
@@ -276,8 +402,13 @@ fn note_decrypt<P: PoolParams>(
     let epk = EdwardsPoint::subgroup_decompress(epk, params.jubjub())?;
     let dh = epk.mul(dk, params.jubjub());
 
+    let (note_vec_enc, mac) = note_data[32..].split_at(note_data.len() - 64);
+    if note_mac(dh.x, &note_data[..32], note_vec_enc) != mac {
+        return None;
+    }
+
     let prefix = dh_prefix(dh.x, &note_data[..32]);
-    let note_vec = xor_crypt(&prefix, &note_data[32..]);
+    let note_vec = xor_crypt(&prefix, note_vec_enc);
 
     let mut hasher = Keccak256::new();
     hasher.update(&note_vec);
@@ -290,38 +421,57 @@ fn note_decrypt<P: PoolParams>(
     {
         None
     } else {
-        Note::try_from_slice(&note_vec).ok()
+        Note::from_slice_with_chunks(&note_vec, params.note_chunks()).ok()
     }
 }
 
-pub fn note_decrypt_in<P: PoolParams>(
-    dk: Num<P::Fs>,
-    msg_data: &[u8],
-    params: &P,
-) -> Option<Note<P::Fr>> {
-    let note_size: usize = NOTE_CHUNKS.iter().sum();
+// epk, epk2 and the encrypted part of a message produced by note_encrypt
+fn parse_encrypted_note<'a, P: PoolParams>(msg_data: &'a [u8], params: &P) -> Option<(Num<P::Fr>, Num<P::Fr>, &'a [u8])> {
     let num_size = (P::Fr::NUM_BITS as usize - 1) / 8 + 1;
-    if msg_data.len() != 32 + 2 * num_size + note_size {
+    if msg_data.len() != encrypted_note_size(params) {
         None
     } else {
         let epk = Num::try_from_slice(&msg_data[0..num_size]).ok()?;
-        note_decrypt(dk, epk, &msg_data[2 * num_size..], params)
+        let epk2 = Num::try_from_slice(&msg_data[num_size..num_size * 2]).ok()?;
+        Some((epk, epk2, &msg_data[2 * num_size..]))
     }
 }
 
+pub fn note_decrypt_in<P: PoolParams>(
+    dk: Num<P::Fs>,
+    msg_data: &[u8],
+    params: &P,
+) -> Option<Note<P::Fr>> {
+    let (epk, _, note_data) = parse_encrypted_note(msg_data, params)?;
+    note_decrypt(dk, epk, note_data, params)
+}
+
 pub fn note_decrypt_out<P: PoolParams>(
     dk: Num<P::Fs>,
     msg_data: &[u8],
     params: &P,
 ) -> Option<Note<P::Fr>> {
-    let note_size: usize = NOTE_CHUNKS.iter().sum();
-    let num_size = (P::Fr::NUM_BITS as usize - 1) / 8 + 1;
-    if msg_data.len() != 32 + 2 * num_size + note_size {
-        None
-    } else {
-        let epk = Num::try_from_slice(&msg_data[num_size..num_size * 2]).ok()?;
-        note_decrypt(dk, epk, &msg_data[2 * num_size..], params)
-    }
+    let (_, epk2, note_data) = parse_encrypted_note(msg_data, params)?;
+    note_decrypt(dk, epk2, note_data, params)
+}
+
+// Tries both incoming and outgoing decryption of every message,
+// returns the indexes of decrypted messages with the notes
+pub fn scan_notes<P: PoolParams>(
+    dk: Num<P::Fs>,
+    messages: &[&[u8]],
+    params: &P,
+) -> Vec<(usize, Note<P::Fr>)> {
+    messages
+        .iter()
+        .enumerate()
+        .filter_map(|(i, msg_data)| {
+            let (epk, epk2, note_data) = parse_encrypted_note(msg_data, params)?;
+            note_decrypt(dk, epk, note_data, params)
+                .or_else(|| note_decrypt(dk, epk2, note_data, params))
+                .map(|note| (i, note))
+        })
+        .collect()
 }
 
 pub fn nullfifier<P: PoolParams>(note_hash: Num<P::Fr>, xsk: Num<P::Fr>, params: &P) -> Num<P::Fr> {
@@ -330,7 +480,7 @@ pub fn nullfifier<P: PoolParams>(note_hash: Num<P::Fr>, xsk: Num<P::Fr>, params:
 
 pub fn note_hash<P: PoolParams>(note: Note<P::Fr>, params: &P) -> Num<P::Fr> {
     poseidon_with_salt(
-        &[note.d, note.pk_d, note.v, note.st],
+        &[note.d, note.pk_d, note.v, note.st, note.asset],
         SEED_NOTE_HASH,
         params.note(),
     )
@@ -388,10 +538,10 @@ pub fn derive_key_pk_d<P: PoolParams>(
     EdwardsPoint::from_scalar(d_hash, params.jubjub()).mul(dk, params.jubjub())
 }
 
-pub fn parse_delta<F: Field>(delta: Num<F>) -> Num<F> {
+pub fn parse_delta<P: PoolParams>(delta: Num<P::Fr>, params: &P) -> Num<P::Fr> {
     let delta_num = Into::<BigUint>::into(delta);
-    let min_neg_amount = BigUint::one() << (NOTE_CHUNKS[2] * 8 - 1);
-    let limit_amount = BigUint::one() << (NOTE_CHUNKS[2] * 8);
+    let min_neg_amount = BigUint::one() << (params.note_chunks()[2] * 8 - 1);
+    let limit_amount = BigUint::one() << (params.note_chunks()[2] * 8);
     assert!(delta_num < limit_amount);
 
     if delta_num < min_neg_amount {
@@ -405,10 +555,11 @@ pub fn parse_delta<F: Field>(delta: Num<F>) -> Num<F> {
 mod tx_test {
     use super::*;
     use crate::native::data::rand_biguint;
-    use crate::POOL_PARAMS;
+    use crate::{POOL_PARAMS, TPoolParams, IN, OUT};
     use fawkes_crypto::native::bn256::Fr;
     use num::BigUint;
     use rand::{thread_rng, Rand, Rng};
+    use std::time::Instant;
 
 
     #[test]
@@ -423,7 +574,7 @@ mod tx_test {
         let r_pk_d = derive_key_pk_d(note.d, r_dk, &*POOL_PARAMS).x;
         note.pk_d = r_pk_d;
 
-        let msg = note_encrypt(esk, dk, note, &*POOL_PARAMS);
+        let msg = note_encrypt(esk, dk, note, &*POOL_PARAMS).unwrap();
         println!("Msg size: {}", msg.len());
 
         let note1 = note_decrypt_out(dk, &msg, &*POOL_PARAMS).unwrap();
@@ -432,4 +583,152 @@ mod tx_test {
         assert!(note == note1, "Decryption for sender should be correct");
         assert!(note == note2, "Decryption for receiver should be correct");
     }
+
+    // 16-byte values
+    const WIDE_NOTE_CHUNKS: [usize; 5] = [10, 32, 16, 10, 4];
+
+    fn wide_params() -> TPoolParams {
+        TPoolParams {
+            note_chunks: WIDE_NOTE_CHUNKS,
+            ..POOL_PARAMS.clone()
+        }
+    }
+
+    #[test]
+    fn test_note_chunks_serialization() {
+        let mut rng = thread_rng();
+        let mut note: Note<Fr> = Note::rand_with_chunks(&mut rng, &WIDE_NOTE_CHUNKS);
+        note.v = num!(rand_biguint(&mut rng, WIDE_NOTE_CHUNKS[2] * 8));
+
+        let data = note.to_vec_with_chunks(&WIDE_NOTE_CHUNKS).unwrap();
+        assert_eq!(data.len(), WIDE_NOTE_CHUNKS.iter().sum::<usize>());
+        assert!(Note::from_slice_with_chunks(&data, &WIDE_NOTE_CHUNKS).unwrap() == note);
+        assert!(Note::<Fr>::from_slice_with_chunks(&data, &NOTE_CHUNKS).is_err());
+
+        // the value doesn't fit into the default layout
+        assert!(note.try_to_vec().is_err());
+
+        let note: Note<Fr> = rng.gen();
+        assert!(Note::try_from_slice(&note.try_to_vec().unwrap()).unwrap() == note);
+    }
+
+    #[test]
+    fn test_note_chunks_encryption() {
+        let mut rng = thread_rng();
+        let params = wide_params();
+        let dk = rng.gen();
+
+        let mut note: Note<Fr> = Note::rand_with_chunks(&mut rng, &WIDE_NOTE_CHUNKS);
+        note.v = num!(rand_biguint(&mut rng, WIDE_NOTE_CHUNKS[2] * 8));
+        note.pk_d = derive_key_pk_d(note.d, dk, &params).x;
+
+        let msg = note_encrypt(rng.gen(), rng.gen(), note, &params).unwrap();
+        assert_eq!(msg.len(), encrypted_note_size(&params));
+        assert!(note_decrypt_in(dk, &msg, &params) == Some(note));
+        assert!(note_decrypt_in(dk, &msg, &*POOL_PARAMS).is_none());
+    }
+
+    #[test]
+    fn test_scan_notes() {
+        let mut rng = thread_rng();
+        let dk = rng.gen();
+        let foreign_dk = rng.gen();
+
+        let mut messages = vec![];
+        let mut expected = vec![];
+        for i in 0..32 {
+            let mut note: Note<Fr> = rng.gen();
+            let (r_dk, s_dk) = match i % 3 {
+                0 => (dk, foreign_dk),
+                1 => (foreign_dk, dk),
+                _ => (foreign_dk, foreign_dk),
+            };
+            note.pk_d = derive_key_pk_d(note.d, r_dk, &*POOL_PARAMS).x;
+            messages.push(note_encrypt(rng.gen(), s_dk, note, &*POOL_PARAMS).unwrap());
+            if i % 3 != 2 {
+                expected.push((i, note));
+            }
+        }
+        messages.push(vec![0u8; 10]);
+        let messages = messages.iter().map(|m| &m[..]).collect::<Vec<_>>();
+
+        let start = Instant::now();
+        let found = scan_notes(dk, &messages, &*POOL_PARAMS);
+        println!("Time elapsed in scan_notes() is: {:?}", start.elapsed());
+        assert!(found == expected);
+
+        let start = Instant::now();
+        let looped = messages.iter().enumerate()
+            .filter_map(|(i, m)| note_decrypt_in(dk, m, &*POOL_PARAMS)
+                .or_else(|| note_decrypt_out(dk, m, &*POOL_PARAMS))
+                .map(|note| (i, note)))
+            .collect::<Vec<_>>();
+        println!("Time elapsed in note_decrypt_in/out loop is: {:?}", start.elapsed());
+        assert!(looped == found);
+    }
+
+    #[test]
+    fn test_encryption_tampered() {
+        let mut rng = thread_rng();
+        let dk = rng.gen();
+        let r_dk = rng.gen();
+
+        let mut note: Note<Fr> = rng.gen();
+        note.pk_d = derive_key_pk_d(note.d, r_dk, &*POOL_PARAMS).x;
+
+        let msg = note_encrypt(rng.gen(), dk, note, &*POOL_PARAMS).unwrap();
+        let num_size = (<Fr as Field>::NUM_BITS as usize - 1) / 8 + 1;
+
+        // the encrypted note and the mac
+        for i in 2 * num_size + 32..msg.len() {
+            let mut tampered = msg.clone();
+            tampered[i] ^= 1;
+            assert!(note_decrypt_in(r_dk, &tampered, &*POOL_PARAMS).is_none());
+            assert!(note_decrypt_out(dk, &tampered, &*POOL_PARAMS).is_none());
+        }
+    }
+
+    #[test]
+    fn test_to_contract_args() {
+        let mut rng = thread_rng();
+        let p = TransferPub::<TPoolParams> {
+            root: rng.gen(),
+            nullifier: (0..IN::USIZE).map(|_| rng.gen()).collect(),
+            out_hash: (0..OUT::USIZE).map(|_| rng.gen()).collect(),
+            delta: num!(-5),
+            deposit_amount: num!(0),
+            withdraw_amount: num!(5),
+            fee: num!(3),
+            memo: rng.gen(),
+            asset: default_asset(),
+        };
+        let message = vec![7u8; 10];
+        let after_root = rng.gen();
+
+        let args = p.to_contract_args(&message, p.root, after_root, Some("dave_near".to_string()));
+        // root, nullifier, out_hash, delta, deposit_amount, fee, memo, asset
+        let num_len = 6 + IN::USIZE + OUT::USIZE;
+        assert_eq!(args.len(), 32 * num_len + 4 + 10 + 2 * 32 + 1 + 4 + 9 + 32);
+        assert!(args[..32] == p.root.try_to_vec().unwrap()[..]);
+        assert!(args[32 * (num_len - 3)..32 * (num_len - 2)] == p.fee.try_to_vec().unwrap()[..]);
+        assert!(args[32 * (num_len - 1)..32 * num_len] == p.asset.try_to_vec().unwrap()[..]);
+        assert!(args[32 * num_len + 4..32 * num_len + 14] == message[..]);
+        assert!(args[32 * num_len + 46..32 * num_len + 78] == after_root.try_to_vec().unwrap()[..]);
+        assert!(args[32 * (num_len - 4)..32 * (num_len - 3)] == p.deposit_amount.try_to_vec().unwrap()[..]);
+        assert!(args[args.len() - 32..] == p.withdraw_amount.try_to_vec().unwrap()[..]);
+
+        // root, nullifier, out_hash, delta, deposit_amount, withdraw_amount, fee, memo, asset
+        let inputs = p.public_inputs();
+        let input_len = 7 + IN::USIZE + OUT::USIZE;
+        let input = |i: usize| &inputs[32 * i..32 * (i + 1)];
+        assert_eq!(inputs.len(), 32 * input_len);
+        // root, nullifier, out_hash, delta and deposit_amount are in the same order as in the contract args
+        assert!(inputs[..32 * (input_len - 4)] == args[..32 * (input_len - 4)]);
+        assert!(input(input_len - 6) == &p.delta.try_to_vec().unwrap()[..]);
+        assert!(input(input_len - 5) == &p.deposit_amount.try_to_vec().unwrap()[..]);
+        assert!(input(input_len - 4) == &p.withdraw_amount.try_to_vec().unwrap()[..]);
+        assert!(input(input_len - 3) == &p.fee.try_to_vec().unwrap()[..]);
+        assert!(input(input_len - 2) == &p.memo.try_to_vec().unwrap()[..]);
+        assert!(input(input_len - 1) == &p.asset.try_to_vec().unwrap()[..]);
+    }
 }
@@ -0,0 +1,95 @@
+use clap::{App, Arg};
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::process::Command;
+
+use crate::native::tx::{TransferPub, TransferSec};
+use crate::TPoolParams;
+
+// Input of prove-from-json, read from stdin. It is the object.json layout of generate-test-data,
+// the serde JSON of the (TransferPub, TransferSec) pair built by the client:
+// [
+//   {
+//     "root": Fr, "nullifier": [Fr; IN], "out_hash": [Fr; OUT], "delta": Fr,
+//     "deposit_amount": Fr, "withdraw_amount": Fr, "fee": Fr, "memo": Fr, "asset": Fr
+//   },
+//   {
+//     "tx": { "input": [Note; IN], "output": [Note; OUT] },
+//     "in_proof": [{ "sibling": [Fr; H], "path": [bool; H] }; IN],
+//     "eddsa_s": Fr, "eddsa_r": Fr, "eddsa_a": Fr
+//   }
+// ]
+// where Note is { "d": Fr, "pk_d": Fr, "v": Fr, "st": Fr, "asset": Fr } and Fr is a decimal string.
+//
+// Output on stdout: { "proof": { "a": G1, "b": G2, "c": G1 }, "inputs": [Fr; 7 + IN + OUT] },
+// the proof.json and inputs.json of the prove command, accepted by verify and by the contract's Proof JSON.
+type ProveInput = (TransferPub<TPoolParams>, TransferSec<TPoolParams>);
+
+fn read_output(path: &std::path::Path) -> serde_json::Value {
+    let data = fs::read_to_string(path).unwrap_or_else(|e| panic!("Cannot read {}: {}", path.display(), e));
+    serde_json::from_str(&data).unwrap_or_else(|e| panic!("Cannot parse {}: {}", path.display(), e))
+}
+
+// pool-prover prove-from-json [--params params.bin] < object.json
+pub fn prove_from_json_main(args: &[String]) {
+    let matches = App::new("prove-from-json")
+        .about("Proves a transaction read from stdin as JSON, prints the proof and the public inputs")
+        .arg(Arg::with_name("params").short('p').long("params").takes_value(true).default_value("params.bin"))
+        .get_matches_from(args);
+
+    let mut data = String::new();
+    io::stdin().read_to_string(&mut data).unwrap_or_else(|e| panic!("Cannot read stdin: {}", e));
+    // checked here, so a malformed object is reported before the prover loads the params
+    let object: ProveInput = serde_json::from_str(&data).unwrap_or_else(|e| panic!("Cannot parse the transaction: {}", e));
+
+    let params = fs::canonicalize(matches.value_of("params").unwrap()).unwrap_or_else(|e| panic!("Cannot find params: {}", e));
+    let dir = env::temp_dir().join(format!("pool-prover-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap_or_else(|e| panic!("Cannot create {}: {}", dir.display(), e));
+    let (object_path, proof_path, inputs_path) = (dir.join("object.json"), dir.join("proof.json"), dir.join("inputs.json"));
+    fs::write(&object_path, serde_json::to_string(&object).unwrap()).unwrap_or_else(|e| panic!("Cannot write {}: {}", object_path.display(), e));
+
+    // the prove command of groth16_near_bindings does the proving
+    let status = Command::new(env::current_exe().unwrap())
+        .arg("prove")
+        .arg("--params").arg(&params)
+        .arg("--object").arg(&object_path)
+        .arg("--proof").arg(&proof_path)
+        .arg("--inputs").arg(&inputs_path)
+        .status()
+        .unwrap_or_else(|e| panic!("Cannot run prove: {}", e));
+    if !status.success() {
+        panic!("prove failed: {}", status);
+    }
+
+    let res = serde_json::json!({
+        "proof": read_output(&proof_path),
+        "inputs": read_output(&inputs_path),
+    });
+    fs::remove_dir_all(&dir).ok();
+    println!("{}", res);
+}
+
+#[cfg(test)]
+mod prover_test {
+    use super::*;
+    use crate::native::data::gen_test_data;
+    use crate::{IN, OUT};
+    use typenum::Unsigned;
+
+    #[test]
+    fn test_prove_input_schema() {
+        let data = serde_json::to_string(&gen_test_data()).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&data).unwrap();
+        assert!(value[0]["root"].is_string());
+        assert_eq!(value[0]["nullifier"].as_array().unwrap().len(), IN::USIZE);
+        assert_eq!(value[0]["out_hash"].as_array().unwrap().len(), OUT::USIZE);
+        assert_eq!(value[1]["tx"]["input"].as_array().unwrap().len(), IN::USIZE);
+        assert!(value[1]["tx"]["output"][0]["pk_d"].is_string());
+        assert!(value[1]["in_proof"][0]["path"][0].is_boolean());
+        assert!(value[1]["eddsa_s"].is_string());
+
+        let object: ProveInput = serde_json::from_str(&data).unwrap();
+        assert_eq!(serde_json::to_string(&object).unwrap(), data);
+    }
+}
@@ -0,0 +1,202 @@
+use bellman::groth16::{prepare_verifying_key, verify_proof, Proof, VerifyingKey};
+use clap::{App, Arg};
+use ff::{Field, PrimeField};
+use num::BigUint;
+use pairing::bn256::{Bn256, Fq, Fq2, Fr, G1Affine, G2Affine};
+use pairing::CurveAffine;
+use std::fs;
+use std::io;
+
+// json layout of the cli output, G2 coordinates are [c0, c1], the contract takes them
+// in the reversed order, see rev_ordering in the contract tests
+type G1Json = [String; 2];
+type G2Json = [[String; 2]; 2];
+
+#[derive(Deserialize)]
+pub struct VkJson {
+    pub alpha_g1: G1Json,
+    pub beta_g2: G2Json,
+    pub gamma_g2: G2Json,
+    pub delta_g2: G2Json,
+    pub ic: Vec<G1Json>,
+}
+
+#[derive(Deserialize)]
+pub struct ProofJson {
+    pub a: G1Json,
+    pub b: G2Json,
+    pub c: G1Json,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn parse_fq(s: &str) -> io::Result<Fq> {
+    Fq::from_str(s).ok_or_else(|| invalid("Wrong field element"))
+}
+
+fn parse_fq2(e: &[String; 2]) -> io::Result<Fq2> {
+    Ok(Fq2 {
+        c0: parse_fq(&e[0])?,
+        c1: parse_fq(&e[1])?,
+    })
+}
+
+// zero coordinates encode the point at infinity
+fn parse_g1(e: &G1Json) -> io::Result<G1Affine> {
+    let (x, y) = (parse_fq(&e[0])?, parse_fq(&e[1])?);
+    if x.is_zero() && y.is_zero() {
+        return Ok(G1Affine::zero());
+    }
+    G1Affine::from_xy_checked(x, y).map_err(|_| invalid("Wrong G1 point"))
+}
+
+fn parse_g2(e: &G2Json) -> io::Result<G2Affine> {
+    let (x, y) = (parse_fq2(&e[0])?, parse_fq2(&e[1])?);
+    if x.is_zero() && y.is_zero() {
+        return Ok(G2Affine::zero());
+    }
+    G2Affine::from_xy_checked(x, y).map_err(|_| invalid("Wrong G2 point"))
+}
+
+// the pairing check of alt_bn128_groth16verify in the contract
+pub fn verify(vk: &VkJson, proof: &ProofJson, inputs: &[String]) -> io::Result<bool> {
+    if vk.ic.len() != inputs.len() + 1 {
+        return Err(invalid("Wrong number of inputs"));
+    }
+
+    let vk = VerifyingKey::<Bn256> {
+        alpha_g1: parse_g1(&vk.alpha_g1)?,
+        // not used by the verifier
+        beta_g1: G1Affine::zero(),
+        beta_g2: parse_g2(&vk.beta_g2)?,
+        gamma_g2: parse_g2(&vk.gamma_g2)?,
+        delta_g1: G1Affine::zero(),
+        delta_g2: parse_g2(&vk.delta_g2)?,
+        ic: vk.ic.iter().map(parse_g1).collect::<io::Result<_>>()?,
+    };
+    let proof = Proof::<Bn256> {
+        a: parse_g1(&proof.a)?,
+        b: parse_g2(&proof.b)?,
+        c: parse_g1(&proof.c)?,
+    };
+    let inputs = inputs
+        .iter()
+        .map(|s| Fr::from_str(s).ok_or_else(|| invalid("Wrong input")))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    verify_proof(&prepare_verifying_key(&vk), &proof, &inputs).map_err(|e| invalid(&e.to_string()))
+}
+
+// 32 bytes little-endian, the borsh layout of U256 in the contract
+fn fq_to_bytes(s: &str) -> io::Result<Vec<u8>> {
+    parse_fq(s)?;
+    let mut res = s.parse::<BigUint>().map_err(|_| invalid("Wrong field element"))?.to_bytes_le();
+    res.resize(32, 0);
+    Ok(res)
+}
+
+// borsh of the contract VK, Fq2 coordinates of G2 points are reversed as by rev_ordering
+pub fn vk_to_contract_bytes(vk: &VkJson) -> io::Result<Vec<u8>> {
+    let mut res = vec![];
+    let push_g1 = |res: &mut Vec<u8>, e: &G1Json| -> io::Result<()> {
+        parse_g1(e)?;
+        for c in e.iter() {
+            res.extend(fq_to_bytes(c)?);
+        }
+        Ok(())
+    };
+    push_g1(&mut res, &vk.alpha_g1)?;
+    for e in [&vk.beta_g2, &vk.gamma_g2, &vk.delta_g2].iter() {
+        parse_g2(e)?;
+        for c in [&e[0][1], &e[0][0], &e[1][1], &e[1][0]].iter() {
+            res.extend(fq_to_bytes(c)?);
+        }
+    }
+    res.extend(&(vk.ic.len() as u32).to_le_bytes());
+    for e in vk.ic.iter() {
+        push_g1(&mut res, e)?;
+    }
+    Ok(res)
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &str) -> T {
+    let data = fs::read_to_string(path).unwrap_or_else(|e| panic!("Cannot read {}: {}", path, e));
+    serde_json::from_str(&data).unwrap_or_else(|e| panic!("Cannot parse {}: {}", path, e))
+}
+
+// pool-prover verify [--vk verification_key.json] [--proof proof.json] [--inputs inputs.json]
+pub fn verify_main(args: &[String]) {
+    let matches = App::new("verify")
+        .about("Verifies a proof against a verification key and public inputs")
+        .arg(Arg::with_name("vk").short('v').long("vk").takes_value(true).default_value("verification_key.json"))
+        .arg(Arg::with_name("proof").short('p').long("proof").takes_value(true).default_value("proof.json"))
+        .arg(Arg::with_name("inputs").short('i').long("inputs").takes_value(true).default_value("inputs.json"))
+        .get_matches_from(args);
+
+    let vk: VkJson = read_json(matches.value_of("vk").unwrap());
+    let proof: ProofJson = read_json(matches.value_of("proof").unwrap());
+    let inputs: Vec<String> = read_json(matches.value_of("inputs").unwrap());
+
+    println!("{}", verify(&vk, &proof, &inputs).unwrap_or_else(|e| panic!("{}", e)));
+}
+
+// pool-prover export-vk [--vk verification_key.json] [--output file], prints base64 if no output is set
+pub fn export_vk_main(args: &[String]) {
+    let matches = App::new("export-vk")
+        .about("Exports a verification key as base64 borsh of the contract VK")
+        .arg(Arg::with_name("vk").short('v').long("vk").takes_value(true).default_value("verification_key.json"))
+        .arg(Arg::with_name("output").short('o').long("output").takes_value(true))
+        .get_matches_from(args);
+
+    let vk: VkJson = read_json(matches.value_of("vk").unwrap());
+    let data = base64::encode(&vk_to_contract_bytes(&vk).unwrap_or_else(|e| panic!("{}", e)));
+    match matches.value_of("output") {
+        Some(path) => fs::write(path, data).unwrap_or_else(|e| panic!("Cannot write {}: {}", path, e)),
+        None => println!("{}", data),
+    }
+}
+
+#[cfg(test)]
+mod verifier_test {
+    use super::*;
+
+    // test vectors of test_verifier in the contract
+    const VK_DATA: &str = r#"{"alpha_g1":["7519284530658385413481729597245720500030404990462629904897898136763311124168","7759305346990060398806855044079194419731758745769222108787862678726666900220"],"beta_g2":[["1176649506803683766765422712992584640052508597600331524207624969164785648052","12658514753455364255847347356482838872202464563210394015283712905609817617215"],["5101165399365558344990622388380303844882446238022873489370271119435994448723","21636711779296379288552588423407018283375205758461617755430164958214291425324"]],"gamma_g2":[["9563259163523751010149091049096621603384601617762202898305068080460508703482","18614172729111398410642152943077024203312410577460779291567515130504309581422"],["12621444442673662801320090583641606322810680442819355704097044349429764048796","17216172718858700295335335730679086396625358808428380281751049509583791629262"]],"delta_g2":[["19725912176908845463228350949600426717278140749628152615121896685037506347011","17691763529654559336605901395565305758251561614530184689342930207658179614206"],["20238568526203996774716543421041972327669035296195845273002576718877215923164","15710570036806237863872618522246158637753680141692137028567816236766869162809"]],"ic":[["3964469773568119779052821042808454784223031204987385635064017202325906327385","2073371815659986312890584980465551647231124680817898465902507207756050662914"],["4200764591093886258865897762205005440549504077177167612462074109108510041154","8210162094715128697891465727401339441835903868694705140685126081493645419200"],["11199418528656095984467893172993547135742541654865846474409172511469530639430","3372662532236438142930946469282900573364450071306042743217169532251229727789"],["10558449468201057926921669993065084153745189931137637082603458431351529160370","15322117100181648633046563406466145990156007727196599836948526707694418130332"]]}"#;
+    const PROOF_DATA: &str = r#"{"a":["21043037212023412872652135275628577069606762281947467181607878147879655369793","11391638837027929377007053995004384561704549985652608308694846372242854188373"],"b":[["14717031433646916944179522170475238687961921977877202038288877044720520170335","11367876662147374721764202840481604860490092766353562008338272528492341756873"],["8547188232587596773995555326777331488318150619810926443278938036838567497339","2288215547019467674158223485296290725771964506608322019365849703537867366708"]],"c":["13775297247810760300790651473298540941951313217152384330093971036306580680406","8848858450888865898290411836574982432732750169946139830040805919604759169863"]}"#;
+    const INPUT_DATA: &str = r#"["6312388174271946628009376311828913110600126535927993427049310256231803794882","19151878342329385484801902211804929466921026268651676084442650160555830671451","16960269216762094114673992166783997514673209322789784124347418911889771148796"]"#;
+
+    // VK_DATA exported by export-vk, deserialized by test_vk_export of the contract
+    const VK_BORSH: &str = "yO5EICtE+JVjTbRYkayI0Y/BoOJtE9lsrqeCTKTDnxD8UjB8B51wrVsQsVrsi6Uk0b2UKGfs3AJUX2Eud5wnET/ze/CsefR74bOn50BmVoExPPDiJGRD2IejItInd/wbtAH7GstyB1Q1j9uROBBAgE2eEj/cwRcHJobe4MP1mQIsiHdC5KXrBZFMlcRQi19O5pRHJ3fra+CNrckf5PHVL1NDT3E4ah/xGoRIbB0DfMSC2AO5NCyuZzJiAMBEKEcLbiazu9JOT65EXSc7HGM9IKlQXgpITR/jikWNxJc/Jyn6KiimTBN/yj4NVjAogElMdLmVoelMa0SAen8Z5ZwkFc6j3IriiWbKQnnkocqd++FqYs4gTh2rFDvcn2YpAhAmnMf35ssgfTFSIOyLZeRQPJ/SzCQMvSq8p1TAkgF85xv+1Vwd0UmrwJXyPVWhevfis0jEd6Cw78ESIMwB7S4dJwNAnVjEBRrKGfOAAzBIiTQRVMSMY2a1nMP/vr57eJwrOYvVboNDUHw8N+u1KoT3vTQkt6+bdeUBw2X/HBbeuyLcmx9AdsbJ0QY1GGF4cgGnSx9kGtcL9UY4qMWVtJ++LAQAAABZB9VFKNzCZgjPMZ9MTfotIL1czmkU9p4L3+6udM/DCAIGsaMeBAN/AhWI+GDLJK3EPzfiVDtw9PWWv+mifJUEQqRUa63wkfB2CouGxTpfsMPlZW93gzGXl5C4lmqMSQnAYpBIHANPM/R/DtA6eMTKKgKBfqgSMjf8YwlmfckmEkbsEZYwsUj2B+ryafp/qj39z80B/33p62Wz+OdwpcIYLSyprNYGC1nyO/jlRIhqRFhx9qkBRjKz/ddvFv7bdAeyPpjCqbT/6zrE22RSdm1I+tceC6xm3OUJE3wX4d5XF5z1EXo17iShXLdYhwVcd//YzyysetRirUxRPeXNAuAh";
+
+    #[test]
+    fn test_vk_export() {
+        let vk: VkJson = serde_json::from_str(VK_DATA).unwrap();
+        let data = vk_to_contract_bytes(&vk).unwrap();
+        assert_eq!(data.len(), 2 * 32 + 3 * 4 * 32 + 4 + vk.ic.len() * 2 * 32);
+        assert_eq!(base64::encode(&data), VK_BORSH);
+
+        // G2 x = [c1, c0]
+        assert!(data[64..96] == fq_to_bytes(&vk.beta_g2[0][1]).unwrap()[..]);
+        assert!(data[96..128] == fq_to_bytes(&vk.beta_g2[0][0]).unwrap()[..]);
+
+        let mut wrong: VkJson = serde_json::from_str(VK_DATA).unwrap();
+        wrong.alpha_g1[1] = wrong.alpha_g1[0].clone();
+        assert!(vk_to_contract_bytes(&wrong).is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        let vk: VkJson = serde_json::from_str(VK_DATA).unwrap();
+        let proof: ProofJson = serde_json::from_str(PROOF_DATA).unwrap();
+        let mut inputs: Vec<String> = serde_json::from_str(INPUT_DATA).unwrap();
+        assert!(verify(&vk, &proof, &inputs).unwrap());
+
+        inputs[0] = "6312388174271946628009376311828913110600126535927993427049310256231803794881".to_string();
+        assert!(!verify(&vk, &proof, &inputs).unwrap());
+
+        inputs.pop();
+        assert!(verify(&vk, &proof, &inputs).is_err());
+    }
+}